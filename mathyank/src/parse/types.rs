@@ -133,6 +133,8 @@ pub enum EquBlockKind {
     Latex,
     GnuPlot,
     GnuPlotOnly,
+    /// A matplotlib script, saved by the preprocessor
+    Python,
     Equation,
 }
 
@@ -142,6 +144,7 @@ impl FromStr for EquBlockKind {
         Ok(match s {
             "gnuplot" => Self::GnuPlot,
             "gnuplotonly" => Self::GnuPlotOnly,
+            "python" | "plot-python" => Self::Python,
             "equ" | "equation" => Self::Equation,
             abbrev => {
                 return Err(Self::Err::UnknownRefKind {
@@ -158,6 +161,7 @@ impl EquBlockKind {
             Self::Latex => ("🌋", "tex"),
             Self::Equation => ("🧮", "equation"),
             Self::GnuPlot | Self::GnuPlotOnly => ("📈", "figure"),
+            Self::Python => ("🐍", "figure"),
         }
     }
 
@@ -66,6 +66,7 @@ pub enum SupportedRenderer {
     Latex,
     Markdown,
     Html,
+    Epub,
 }
 
 impl FromStr for SupportedRenderer {
@@ -77,6 +78,7 @@ impl FromStr for SupportedRenderer {
             "latex" => Self::Latex,
             "markdown" => Self::Markdown,
             "html" => Self::Html,
+            "epub" => Self::Epub,
             s => return Err(errors::Error::RendererNotSupported(s.to_owned())),
         })
     }
@@ -89,6 +91,7 @@ impl SupportedRenderer {
             Self::Tectonic => "tectonic",
             Self::Latex => "latex",
             Self::Markdown => "markdown",
+            Self::Epub => "epub",
         }
     }
 }
@@ -98,26 +101,37 @@ impl fmt::Display for SupportedRenderer {
     }
 }
 
+/// Command line of a preprocessor, `E` are the subcommands beyond `supports`
 #[derive(clap::Parser, Debug)]
 #[command(author, version, about)]
-pub struct Args {
+pub struct ArgsWith<E: clap::Subcommand> {
     #[command(subcommand)]
-    pub supports: Option<Sub>,
+    pub supports: Option<Sub<E>>,
 }
 
+/// Command line of a preprocessor with the `supports` subcommand only
+pub type Args = ArgsWith<NoExtra>;
+
 #[derive(clap::Subcommand, Debug)]
-#[doc = "Check whether a renderer is supported by this preprocessor"]
-pub enum Sub {
+pub enum Sub<E: clap::Subcommand = NoExtra> {
+    /// Check whether a renderer is supported by this preprocessor
     Supports { renderer: String },
+    #[command(flatten)]
+    Extra(E),
 }
 
+/// No subcommands beyond `supports`
+#[derive(clap::Subcommand, Debug)]
+pub enum NoExtra {}
+
 use std::fmt;
-impl fmt::Display for Sub {
+impl<E: clap::Subcommand + fmt::Debug> fmt::Display for Sub<E> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Sub::Supports { renderer } => {
                 write!(f, "{}", renderer.as_str())
             }
+            Sub::Extra(extra) => write!(f, "{:?}", extra),
         }
     }
 }
@@ -165,15 +179,34 @@ pub fn launch<Pre: Preprocessor + 'static>(
     args: impl Into<Args>,
     prefix: &'static str,
 ) -> color_eyre::eyre::Result<()> {
-    setup_log_and_backtrace(preprocessor.name(), prefix)?;
+    launch_with(preprocessor, args.into(), prefix, |extra| match extra {})
+}
 
-    let args = args.into();
-    if let Some(Sub::Supports { ref renderer }) = args.supports {
-        handle_supports(&preprocessor, renderer);
-    } else {
-        handle_preprocessing(&preprocessor)?;
+/// Like [`launch`], running `extra` for the subcommands beyond `supports`.
+///
+/// Their output is their own, so they only log on request via `RUST_LOG`.
+pub fn launch_with<Pre: Preprocessor + 'static, E: clap::Subcommand>(
+    preprocessor: Pre,
+    args: ArgsWith<E>,
+    prefix: &'static str,
+    extra: impl FnOnce(E) -> color_eyre::eyre::Result<()>,
+) -> color_eyre::eyre::Result<()> {
+    match args.supports {
+        Some(Sub::Extra(command)) => {
+            color_eyre::install()?;
+            env_logger::init();
+            extra(command)
+        }
+        Some(Sub::Supports { ref renderer }) => {
+            setup_log_and_backtrace(preprocessor.name(), prefix)?;
+            handle_supports(&preprocessor, renderer);
+        }
+        None => {
+            setup_log_and_backtrace(preprocessor.name(), prefix)?;
+            handle_preprocessing(&preprocessor)?;
+            Ok(())
+        }
     }
-    Ok(())
 }
 
 pub fn check_version_compat(name: &'_ str, compat: &'_ str, plugin: &'_ str) -> Result<()> {
@@ -192,7 +225,12 @@ pub fn check_version_compat(name: &'_ str, compat: &'_ str, plugin: &'_ str) ->
 }
 
 fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<()> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin()).map_err(Error::MdBook)?;
+    preprocess_io(pre, io::stdin(), io::stdout())
+}
+
+/// Read the context and book from `input`, write the processed book to `output`
+fn preprocess_io(pre: &dyn Preprocessor, input: impl io::Read, output: impl Write) -> Result<()> {
+    let (ctx, book) = CmdPreprocessor::parse_input(input).map_err(Error::MdBook)?;
 
     check_version_compat(
         pre.name(),
@@ -204,7 +242,7 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<()> {
 
     let processed_book = pre.run(&ctx, book).map_err(Error::MdBook)?;
 
-    serde_json::to_writer(io::stdout(), &processed_book)?;
+    serde_json::to_writer(output, &processed_book)?;
 
     Ok(())
 }
@@ -237,6 +275,69 @@ mod tests {
         });
     }
 
+    struct Counting(std::cell::Cell<usize>);
+
+    impl Preprocessor for Counting {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn run(
+            &self,
+            _ctx: &mdbook::preprocess::PreprocessorContext,
+            book: mdbook::book::Book,
+        ) -> mdbook::errors::Result<mdbook::book::Book> {
+            self.0.set(self.0.get() + 1);
+            Ok(book)
+        }
+    }
+
+    #[test]
+    fn epub_reaches_the_preprocessor() {
+        let input = serde_json::json!([
+            {
+                "root": "/tmp/book",
+                "config": mdbook::Config::default(),
+                "renderer": "epub",
+                "mdbook_version": mdbook::MDBOOK_VERSION,
+            },
+            mdbook::book::Book::new(),
+        ]);
+        let pre = Counting(Default::default());
+        let mut output = Vec::new();
+        preprocess_io(&pre, input.to_string().as_bytes(), &mut output).unwrap();
+        assert_eq!(pre.0.get(), 1);
+        assert_matches!(
+            serde_json::from_slice::<mdbook::book::Book>(&output),
+            Ok(book) => assert!(book.sections.is_empty())
+        );
+    }
+
+    #[derive(clap::Subcommand, Debug, PartialEq)]
+    enum Extra {
+        Check { dir: String },
+    }
+
+    #[test]
+    fn clap_extra_subcommands() {
+        assert_matches!(
+            ArgsWith::<Extra>::try_parse_from(vec!["mdbook-foo", "check", "book"]).unwrap(),
+            ArgsWith { supports: Some(Sub::Extra(extra)) } => {
+                assert_eq!(extra, Extra::Check { dir: "book".to_owned() });
+            }
+        );
+        assert_matches!(
+            ArgsWith::<Extra>::try_parse_from(vec!["mdbook-foo", "supports", "html"]).unwrap(),
+            ArgsWith { supports: Some(Sub::Supports { renderer }) } => {
+                assert_eq!(renderer, "html");
+            }
+        );
+        assert_matches!(
+            Args::try_parse_from(vec!["mdbook-foo", "check", "book"]),
+            Err(_)
+        );
+    }
+
     #[test]
     fn clap_supports_no_sub() {
         assert_matches!(Args::try_parse_from(vec!["mdbook-foo"]).unwrap(),
@@ -0,0 +1,363 @@
+//! Selection, numbering and placement of bibliography entries for the html and markdown
+//! renderers. The latex based renderers handle `bibtex` natively.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use mathyank::*;
+use mdbook::book::{Book, BookItem};
+use mdbook_boilerplate::Table;
+use regex::Regex;
+
+use crate::config;
+use crate::errors::{Result, ScientificError};
+
+/// Which entries of the `.bib` file are listed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BibliographyEntries {
+    /// Every entry of the `.bib` file.
+    #[default]
+    All,
+    /// Only entries referenced via `$ref:bib:..$` somewhere in the book.
+    Cited,
+}
+
+impl FromStr for BibliographyEntries {
+    type Err = ScientificError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "all" => Self::All,
+            "cited" => Self::Cited,
+            s => {
+                return Err(ScientificError::InvalidConfigValue {
+                    key: "bibliography_entries".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// How entries are numbered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BibliographyOrder {
+    /// In the order they appear in the `.bib` file.
+    #[default]
+    File,
+    /// In the order of their first citation, uncited entries trail in file order.
+    Citation,
+}
+
+impl FromStr for BibliographyOrder {
+    type Err = ScientificError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "file" => Self::File,
+            "citation" => Self::Citation,
+            s => {
+                return Err(ScientificError::InvalidConfigValue {
+                    key: "bibliography_order".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// Where the bibliography is placed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BibliographyPlacement {
+    /// A dedicated `Bibliography` chapter at the end of the book.
+    #[default]
+    Book,
+    /// A `References` section at the end of each chapter, listing the entries cited in it.
+    Chapter,
+    /// Both of the above.
+    Both,
+}
+
+impl FromStr for BibliographyPlacement {
+    type Err = ScientificError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "book" => Self::Book,
+            "chapter" => Self::Chapter,
+            "both" => Self::Both,
+            s => {
+                return Err(ScientificError::InvalidConfigValue {
+                    key: "bibliography_placement".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+impl BibliographyPlacement {
+    pub fn has_book_chapter(&self) -> bool {
+        matches!(self, Self::Book | Self::Both)
+    }
+
+    pub fn has_chapter_sections(&self) -> bool {
+        matches!(self, Self::Chapter | Self::Both)
+    }
+
+    /// The page citations link to, empty if it is the citing page itself.
+    pub fn link_target(&self) -> &'static str {
+        if self.has_chapter_sections() {
+            ""
+        } else {
+            "bibliography.html"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BibliographyConfig {
+    pub entries: BibliographyEntries,
+    pub order: BibliographyOrder,
+    pub placement: BibliographyPlacement,
+}
+
+impl BibliographyConfig {
+    pub fn from_cfg(cfg: &Table) -> Result<Self> {
+        Ok(Self {
+            entries: config::get_parsed(cfg, "bibliography_entries")?.unwrap_or_default(),
+            order: config::get_parsed(cfg, "bibliography_order")?.unwrap_or_default(),
+            placement: config::get_parsed(cfg, "bibliography_placement")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// All `$ref:bib:..$` citations of a book.
+#[derive(Debug, Default, Clone)]
+pub struct Citations {
+    /// Citation keys in order of their first citation.
+    pub order: Vec<String>,
+    /// Citation keys per chapter path, in order of their first citation within the chapter.
+    pub per_chapter: HashMap<PathBuf, Vec<String>>,
+}
+
+/// Collect all citations of the book, in reading order, before any chapter is transformed.
+pub fn collect_citations(book: &Book) -> Citations {
+    let mut citations = Citations::default();
+    let mut seen = HashSet::new();
+    for item in book.iter() {
+        if let BookItem::Chapter(ch) = item {
+            let chapter_path = ch.path.clone().unwrap_or_default();
+            let source = ch.content.as_str();
+            let iter = dollar_split_tags_iter(source);
+            for tagged in iter_over_dollar_encompassed_blocks(source, iter) {
+                let content = match tagged {
+                    Tagged::Replace(content) => content,
+                    Tagged::Keep(_) => continue,
+                };
+                if content.start_del.is_block() || content.end_del.is_block() {
+                    continue;
+                }
+                if let Ok(Inline::Reference(Reference {
+                    ref_kind: RefKind::Bibliography,
+                    refere,
+                })) = Inline::try_from(&content)
+                {
+                    if seen.insert(refere.to_owned()) {
+                        citations.order.push(refere.to_owned());
+                    }
+                    let in_chapter = citations
+                        .per_chapter
+                        .entry(chapter_path.clone())
+                        .or_default();
+                    if !in_chapter.iter().any(|key| key == refere) {
+                        in_chapter.push(refere.to_owned());
+                    }
+                }
+            }
+        }
+    }
+    citations
+}
+
+/// A listed bibliography entry.
+#[derive(Debug, Clone)]
+struct Entry {
+    key: String,
+    /// `<dt>..</dt><dd>..</dd>` as generated by `bib2xhtml`, with the label replaced
+    html: String,
+}
+
+/// The selected and numbered bibliography entries.
+#[derive(Debug, Clone)]
+pub struct Bibliography {
+    /// Listed entries, the index plus one is the number of the entry.
+    entries: Vec<Entry>,
+}
+
+impl Bibliography {
+    /// Select and number the entries.
+    ///
+    /// `keys` are all citation keys in `.bib` file order, `html` is the output of
+    /// [`crate::fragments::bib_to_html`].
+    pub fn new(
+        keys: &[String],
+        html: &str,
+        cfg: &BibliographyConfig,
+        citations: &Citations,
+    ) -> Self {
+        let cited = HashSet::<&str>::from_iter(citations.order.iter().map(String::as_str));
+        let selected = match cfg.order {
+            BibliographyOrder::File => Vec::from_iter(
+                keys.iter()
+                    .filter(|key| {
+                        cfg.entries == BibliographyEntries::All || cited.contains(key.as_str())
+                    })
+                    .cloned(),
+            ),
+            BibliographyOrder::Citation => {
+                let mut selected = Vec::from_iter(
+                    citations
+                        .order
+                        .iter()
+                        .filter(|key| keys.contains(*key))
+                        .cloned(),
+                );
+                if cfg.entries == BibliographyEntries::All {
+                    selected.extend(
+                        keys.iter()
+                            .filter(|key| !cited.contains(key.as_str()))
+                            .cloned(),
+                    );
+                }
+                selected
+            }
+        };
+
+        let mut generated = split_bib2xhtml(html);
+        let anchor = bib2xhtml_label();
+        let entries = Vec::from_iter(selected.into_iter().enumerate().map(|(idx, key)| {
+            let html = generated
+                .remove(&key)
+                .map(|html| relabel(&anchor, &html, idx + 1))
+                .unwrap_or_default();
+            Entry { key, html }
+        }));
+        Self { entries }
+    }
+
+    /// Labels to register with the [`crate::ReferenceTracker`].
+    pub fn labels(&self) -> impl Iterator<Item = (&str, String)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| (entry.key.as_str(), label(idx + 1)))
+    }
+
+    /// Render all listed entries.
+    pub fn render(&self) -> String {
+        render_entries(self.entries.iter())
+    }
+
+    /// Render the listed entries that are part of `keys`, ordered by their number.
+    pub fn render_subset(&self, keys: &[String]) -> String {
+        render_entries(self.entries.iter().filter(|entry| keys.contains(&entry.key)))
+    }
+}
+
+fn label(number: usize) -> String {
+    format!("[{}]", number)
+}
+
+fn render_entries<'a>(entries: impl Iterator<Item = &'a Entry>) -> String {
+    let mut html = r#"<dl class="bib2xhtml">"#.to_owned();
+    entries.for_each(|entry| html.push_str(&entry.html));
+    html.push_str("</dl>");
+    html
+}
+
+/// Split the `bib2xhtml` definition list into its entries, by citation key.
+fn split_bib2xhtml(html: &str) -> HashMap<String, String> {
+    let key = Regex::new(r#"<a id="([^"]+)">"#).expect("Regex is valid. qed");
+    HashMap::from_iter(html.split("<dt>").skip(1).filter_map(|chunk| {
+        let html = format!("<dt>{}", chunk);
+        key.captures(chunk)
+            .map(|captures| (captures[1].to_owned(), html))
+    }))
+}
+
+/// The `bib2xhtml` generated label of an entry, i.e. `[A20]`
+fn bib2xhtml_label() -> Regex {
+    Regex::new(r#"^(<dt><a id="[^"]+">)\[[^\]]*\]"#).expect("Regex is valid. qed")
+}
+
+/// Replace the `bib2xhtml` generated label, matched by `anchor`, with the entry number.
+fn relabel(anchor: &Regex, html: &str, number: usize) -> String {
+    anchor
+        .replace(html, |captures: &regex::Captures| {
+            format!("{}{}", &captures[1], label(number))
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<dl class="bib2xhtml"><!-- Authors: A --><dt><a id="a">[A20]</a></dt><dd>A.</dd><!-- Authors: B --><dt><a id="b">[B21]</a></dt><dd>B.</dd><dt><a id="c">[C22]</a></dt><dd>C.</dd>"#;
+
+    fn keys() -> Vec<String> {
+        vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+    }
+
+    fn citations() -> Citations {
+        Citations {
+            order: vec!["c".to_owned(), "a".to_owned()],
+            per_chapter: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn file_order_all() {
+        let bib = Bibliography::new(&keys(), HTML, &BibliographyConfig::default(), &citations());
+        assert_eq!(
+            Vec::from_iter(bib.labels()),
+            vec![
+                ("a", "[1]".to_owned()),
+                ("b", "[2]".to_owned()),
+                ("c", "[3]".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn citation_order_cited_only() {
+        let cfg = BibliographyConfig {
+            entries: BibliographyEntries::Cited,
+            order: BibliographyOrder::Citation,
+            ..Default::default()
+        };
+        let bib = Bibliography::new(&keys(), HTML, &cfg, &citations());
+        assert_eq!(
+            Vec::from_iter(bib.labels()),
+            vec![("c", "[1]".to_owned()), ("a", "[2]".to_owned())]
+        );
+        let html = bib.render();
+        assert!(html.contains(r#"<dt><a id="c">[1]</a></dt><dd>C.</dd>"#));
+        assert!(html.contains(r#"<dt><a id="a">[2]</a></dt>"#));
+        assert!(!html.contains(r#"id="b""#));
+    }
+
+    #[test]
+    fn subset_keeps_numbering() {
+        let cfg = BibliographyConfig {
+            order: BibliographyOrder::Citation,
+            ..Default::default()
+        };
+        let bib = Bibliography::new(&keys(), HTML, &cfg, &citations());
+        let html = bib.render_subset(&["b".to_owned()]);
+        assert!(html.contains(r#"<dt><a id="b">[3]</a></dt>"#));
+        assert!(!html.contains(r#"id="a""#));
+    }
+}
@@ -0,0 +1,490 @@
+//! Manifest of all rendered fragments, to decide when to re-render and which fragments are garbage.
+//!
+//! Fragments form a store addressed by their content, shared by all chapters. The manifest
+//! indexes which chapters use which fragments, per renderer. Stored as `manifest.json` within
+//! the fragment path.
+//!
+//! The substituted chapters are kept in `chapters.json` alongside, so `mdbook serve` only
+//! processes the chapters that changed.
+
+use fs_err as fs;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use mdbook_boilerplate::find_program;
+
+use crate::errors::Result;
+use crate::fragments::Fragment;
+use crate::runner::Runner;
+
+const MANIFEST: &str = "manifest.json";
+const CHAPTERS: &str = "chapters.json";
+
+/// What a fragment was rendered from and what it produced.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Hash over the kind, zoom, source and template
+    pub inputs: String,
+    /// First line of `--version` of every involved tool
+    pub tools: BTreeMap<String, String>,
+    /// Files created for the fragment, relative to the fragment path
+    pub outputs: Vec<String>,
+}
+
+/// The fragments each chapter uses, by chapter source path and fragment file name
+pub type ChapterIndex = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// By fragment file name
+    entries: BTreeMap<String, ManifestEntry>,
+    /// The chapter index of the most recent run, by renderer
+    #[serde(default)]
+    index: BTreeMap<String, ChapterIndex>,
+    #[serde(skip)]
+    fragment_path: PathBuf,
+}
+
+/// Versions of the external tools, queried at most once per run.
+#[derive(Debug, Default)]
+pub struct ToolVersions {
+    versions: HashMap<&'static str, Option<String>>,
+    runner: Runner,
+}
+
+impl ToolVersions {
+    pub fn new(runner: Runner) -> Self {
+        Self {
+            versions: HashMap::new(),
+            runner,
+        }
+    }
+
+    /// The version of `tool`, `None` if it is not installed.
+    pub fn get(&mut self, tool: &'static str) -> Option<&str> {
+        let runner = &self.runner;
+        self.versions
+            .entry(tool)
+            .or_insert_with(|| {
+                let path = find_program(tool).ok()?;
+                let output = runner.run(Command::new(path).arg("--version"), None).ok()?;
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let version = stdout.lines().next().unwrap_or_default().trim().to_owned();
+                log::debug!("Found {} version `{}`", tool, version);
+                Some(version)
+            })
+            .as_deref()
+    }
+}
+
+impl Manifest {
+    /// Load the manifest of the fragment path, starting over if there is none or it's unreadable.
+    pub fn load(fragment_path: &Path) -> Self {
+        let path = fragment_path.join(MANIFEST);
+        let mut manifest = fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| {
+                serde_json::from_str::<Self>(&json)
+                    .map_err(|err| {
+                        log::warn!(
+                            "Discarding unreadable fragment manifest {}: {}",
+                            path.display(),
+                            err
+                        )
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+        manifest.fragment_path = fragment_path.to_owned();
+        manifest
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(self.fragment_path.join(MANIFEST), json)?;
+        Ok(())
+    }
+
+    /// Select the fragments that need rendering and remove their outdated outputs.
+    ///
+    /// A fragment is up to date if its inputs and tool versions match the manifest and all of
+    /// its outputs still exist. Missing tools don't invalidate, so cached fragments can be
+    /// reused on machines without them.
+    pub fn stale<'f>(
+        &self,
+        fragments: &'f [Fragment],
+        tools: &mut ToolVersions,
+    ) -> Result<Vec<&'f Fragment>> {
+        let mut stale = Vec::new();
+        let mut on_disk = self.outputs_on_disk()?;
+        for fragment in fragments {
+            let name = fragment.store_name();
+            let fresh = self.entries.get(&name).filter(|entry| {
+                entry.inputs == fragment.input_hash()
+                    && fragment.tools().into_iter().all(|tool| {
+                        match (tools.get(tool), entry.tools.get(tool)) {
+                            (Some(current), Some(recorded)) => current == recorded,
+                            (Some(_), None) => false,
+                            (None, _) => true,
+                        }
+                    })
+                    && entry
+                        .outputs
+                        .iter()
+                        .all(|output| self.fragment_path.join(output).exists())
+                    && fragment.fragment_file.exists()
+            });
+            if fresh.is_some() {
+                log::debug!("Fragment {} is up to date", name);
+                continue;
+            }
+            log::debug!("Fragment {} is outdated", name);
+            for output in on_disk.remove(stem(fragment)).unwrap_or_default() {
+                fs::remove_file(self.fragment_path.join(output))?;
+            }
+            stale.push(fragment);
+        }
+        Ok(stale)
+    }
+
+    /// Record rendered fragments, those that failed to produce their output are skipped.
+    pub fn record<'f>(
+        &mut self,
+        fragments: impl IntoIterator<Item = &'f Fragment>,
+        tools: &mut ToolVersions,
+    ) -> Result<()> {
+        let mut on_disk = self.outputs_on_disk()?;
+        for fragment in fragments {
+            if !fragment.fragment_file.exists() {
+                continue;
+            }
+            let entry = ManifestEntry {
+                inputs: fragment.input_hash(),
+                tools: BTreeMap::from_iter(fragment.tools().into_iter().filter_map(|tool| {
+                    tools
+                        .get(tool)
+                        .map(|version| (tool.to_owned(), version.to_owned()))
+                })),
+                outputs: on_disk.remove(stem(fragment)).unwrap_or_default(),
+            };
+            self.entries.insert(fragment.store_name(), entry);
+        }
+        Ok(())
+    }
+
+    /// What the recorded output of `fragment` was made from, its inputs and the tool versions.
+    ///
+    /// Changes whenever the fragment is rendered anew, as part of the key of chapters using it.
+    pub fn provenance(&self, fragment: &Fragment) -> String {
+        let tools = self
+            .entries
+            .get(&fragment.store_name())
+            .map(|entry| &entry.tools);
+        format!("{}\0{:?}", fragment.input_hash(), tools)
+    }
+
+    /// Replace the chapter index of `renderer` by the one of its current run.
+    pub fn update_index(&mut self, renderer: &str, index: ChapterIndex) {
+        self.index.insert(renderer.to_owned(), index);
+    }
+
+    /// Delete the fragments no chapter of any renderer uses any more, including their copies
+    /// in the asset path, and the fragments earlier versions named by chapter, i.e.
+    /// `scientific_1_2___0123456789.svg`.
+    pub fn collect_garbage(&mut self, asset_path: &Path) -> Result<()> {
+        let used = HashSet::<&String>::from_iter(
+            self.index
+                .values()
+                .flat_map(|index| index.values().flatten()),
+        );
+        let garbage = Vec::from_iter(
+            self.entries
+                .keys()
+                .filter(|name| !used.contains(name))
+                .cloned(),
+        );
+        for name in garbage {
+            let entry = self
+                .entries
+                .remove(&name)
+                .expect("Name was just taken from the entries. qed");
+            log::info!("Removing unused fragment {}", name);
+            for output in entry.outputs {
+                remove_if_exists(&self.fragment_path.join(output))?;
+            }
+            if self.fragment_path != asset_path {
+                remove_if_exists(&asset_path.join(&name))?;
+            }
+        }
+        // only the fragment path is ours, the asset path may hold files of the user
+        let legacy = Regex::new(r"^scientific_[0-9_]*__[0-9a-f]{10}[.-][\w.-]*$")
+            .expect("Regex is valid. qed");
+        for dir_entry in fs::read_dir(&self.fragment_path)? {
+            let path = dir_entry?.path();
+            let is_legacy = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| legacy.is_match(name));
+            if is_legacy && path.is_file() {
+                log::info!("Removing fragment {} of an earlier version", path.display());
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All files in the fragment path, like `.tex`, `.dvi` and `.svg`, by the fragment they
+    /// belong to.
+    fn outputs_on_disk(&self) -> Result<HashMap<String, Vec<String>>> {
+        let mut outputs = HashMap::<String, Vec<String>>::new();
+        for dir_entry in fs::read_dir(&self.fragment_path)? {
+            let name = dir_entry?.file_name().to_string_lossy().into_owned();
+            // `scientific_<hash>-1.svg` belongs to `scientific_<hash>`, as does `.tex`
+            let stem = name
+                .split(['.', '-'])
+                .next()
+                .expect("Split yields at least one item. qed");
+            outputs.entry(stem.to_owned()).or_default().push(name);
+        }
+        for names in outputs.values_mut() {
+            names.sort();
+        }
+        Ok(outputs)
+    }
+}
+
+/// The name of the fragment file without extension, shared by all of its outputs
+fn stem(fragment: &Fragment) -> &str {
+    fragment
+        .fragment_file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .expect("Fragment file always has a UTF-8 name. qed")
+}
+
+/// The substituted content of a chapter, reused as long as nothing it depends on changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedChapter {
+    /// Hash over the chapter source and everything its substitution depends on
+    pub key: String,
+    pub content: String,
+    /// Fragment files the content refers to, to be copied to the asset path
+    pub fragments: Vec<PathBuf>,
+}
+
+/// Substituted chapters of the previous runs, by renderer and chapter source path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChapterCache {
+    chapters: BTreeMap<String, BTreeMap<String, CachedChapter>>,
+    /// Chapters of the current run, replacing those of the renderer when saved
+    #[serde(skip)]
+    current: BTreeMap<String, CachedChapter>,
+    #[serde(skip)]
+    renderer: String,
+    #[serde(skip)]
+    fragment_path: PathBuf,
+}
+
+impl ChapterCache {
+    /// Load the chapters cached for `renderer`, starting over if there are none or they are
+    /// unreadable.
+    pub fn load(fragment_path: &Path, renderer: &str) -> Self {
+        let path = fragment_path.join(CHAPTERS);
+        let mut cache = fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| {
+                serde_json::from_str::<Self>(&json)
+                    .map_err(|err| {
+                        log::warn!(
+                            "Discarding unreadable chapter cache {}: {}",
+                            path.display(),
+                            err
+                        )
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+        cache.renderer = renderer.to_owned();
+        cache.fragment_path = fragment_path.to_owned();
+        cache
+    }
+
+    /// The cached chapter, if it was substituted with the same `key` before.
+    pub fn get(&self, chapter: &str, key: &str) -> Option<&CachedChapter> {
+        self.chapters
+            .get(&self.renderer)
+            .and_then(|chapters| chapters.get(chapter))
+            .filter(|cached| cached.key == key)
+    }
+
+    /// Keep the chapter for the next run, chapters not kept in this run are dropped on save.
+    pub fn keep(&mut self, chapter: &str, cached: CachedChapter) {
+        self.current.insert(chapter.to_owned(), cached);
+    }
+
+    pub fn save(mut self) -> Result<()> {
+        let current = std::mem::take(&mut self.current);
+        self.chapters.insert(self.renderer.clone(), current);
+        let json = serde_json::to_string(&self)?;
+        fs::write(self.fragment_path.join(CHAPTERS), json)?;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TexEngine;
+    use crate::template::FragmentTemplate;
+    use crate::tests::TempDir;
+    use crate::types::{Origin, SvgEmbedding};
+    use mathyank::EquBlockKind;
+
+    fn fragment(fragment_path: &Path, name: &str) -> Fragment {
+        Fragment {
+            kind: EquBlockKind::GnuPlotOnly,
+            zoom: 1.0,
+            source: name.to_owned(),
+            fragment_file: fragment_path.join(name).with_extension("svg"),
+            asset_file: PathBuf::from(name).with_extension("svg"),
+            origin: Origin {
+                chapter_path: PathBuf::from("src/chapter.md"),
+                lineno: 1,
+                column: 1,
+                lines: Vec::new(),
+            },
+            engine: TexEngine::default(),
+            template: FragmentTemplate::default(),
+            embedding: SvgEmbedding::default(),
+            follow_text_color: false,
+            raster_dpi: None,
+            data: Vec::new(),
+        }
+    }
+
+    fn index(chapters: &[(&str, &[&Fragment])]) -> ChapterIndex {
+        ChapterIndex::from_iter(chapters.iter().map(|(chapter, used)| {
+            (
+                chapter.to_string(),
+                BTreeSet::from_iter(used.iter().map(|fragment| fragment.store_name())),
+            )
+        }))
+    }
+
+    #[test]
+    fn garbage_is_shared_between_renderers() {
+        let dest = TempDir::new("cache-gc");
+
+        let a = fragment(&dest, "a");
+        let b = fragment(&dest, "b");
+        fs::write(&a.fragment_file, "<svg/>").unwrap();
+        fs::write(&b.fragment_file, "<svg/>").unwrap();
+        fs::write(dest.join("a.tex"), "a").unwrap();
+        let legacy = dest.join("scientific_1_2___0123456789.svg");
+        fs::write(&legacy, "<svg/>").unwrap();
+        // not of the legacy shape
+        let own = dest.join("scientific_fig__v2.png");
+        fs::write(&own, "png").unwrap();
+
+        let mut tools = ToolVersions::default();
+        let mut manifest = Manifest::load(&dest);
+        manifest.record([&a], &mut tools).unwrap();
+        assert_eq!(manifest.entries["a.svg"].outputs, ["a.svg", "a.tex"]);
+        manifest.update_index("html", index(&[("src/ch1.md", &[&a])]));
+        manifest.collect_garbage(&dest).unwrap();
+        assert!(!legacy.exists());
+        assert!(own.exists());
+        manifest.record([&a, &b], &mut tools).unwrap();
+        manifest.update_index(
+            "latex",
+            index(&[("src/ch1.md", &[&a]), ("src/ch2.md", &[&a, &b])]),
+        );
+        manifest.collect_garbage(&dest).unwrap();
+        manifest.save().unwrap();
+
+        // `html` does not use `b` any more, but `latex` still does
+        let mut manifest = Manifest::load(&dest);
+        manifest.update_index("html", index(&[("src/ch1.md", &[&a])]));
+        manifest.collect_garbage(&dest).unwrap();
+        assert!(b.fragment_file.exists());
+
+        // `a` is still used by the first chapter
+        manifest.update_index(
+            "latex",
+            index(&[("src/ch1.md", &[&a]), ("src/ch2.md", &[])]),
+        );
+        manifest.collect_garbage(&dest).unwrap();
+        assert!(a.fragment_file.exists());
+        assert!(!b.fragment_file.exists());
+    }
+
+    #[test]
+    fn provenance_follows_tool_versions() {
+        let dest = TempDir::new("cache-tools");
+
+        let a = fragment(&dest, "a");
+        fs::write(&a.fragment_file, "<svg/>").unwrap();
+        let mut tools = ToolVersions::default();
+        tools
+            .versions
+            .insert("gnuplot", Some("gnuplot 5.4 patchlevel 2".to_owned()));
+        let mut manifest = Manifest::load(&dest);
+        manifest.record([&a], &mut tools).unwrap();
+        let before = manifest.provenance(&a);
+
+        tools
+            .versions
+            .insert("gnuplot", Some("gnuplot 6.0 patchlevel 0".to_owned()));
+        assert_eq!(
+            manifest
+                .stale(std::slice::from_ref(&a), &mut tools)
+                .unwrap()
+                .len(),
+            1
+        );
+        fs::write(&a.fragment_file, "<svg/>").unwrap();
+        manifest.record([&a], &mut tools).unwrap();
+        assert_ne!(manifest.provenance(&a), before);
+    }
+
+    #[test]
+    fn chapters_are_reused_by_key() {
+        let dest = TempDir::new("cache-chapters");
+
+        let cached = |key: &str| CachedChapter {
+            key: key.to_owned(),
+            content: format!("substituted {}", key),
+            fragments: vec![dest.join("scientific_abc.svg")],
+        };
+        let mut cache = ChapterCache::load(&dest, "html");
+        cache.keep("src/ch1.md", cached("one"));
+        cache.keep("src/ch2.md", cached("two"));
+        cache.save().unwrap();
+        let mut cache = ChapterCache::load(&dest, "latex");
+        cache.keep("src/ch1.md", cached("latex"));
+        cache.save().unwrap();
+
+        let mut cache = ChapterCache::load(&dest, "html");
+        assert_eq!(cache.get("src/ch1.md", "one"), Some(&cached("one")));
+        assert_eq!(cache.get("src/ch1.md", "changed"), None);
+        // the second chapter was removed from the book
+        let reused = cache.get("src/ch1.md", "one").cloned().unwrap();
+        cache.keep("src/ch1.md", reused);
+        cache.save().unwrap();
+
+        let cache = ChapterCache::load(&dest, "html");
+        assert_eq!(cache.get("src/ch2.md", "two"), None);
+        let cache = ChapterCache::load(&dest, "latex");
+        assert_eq!(cache.get("src/ch1.md", "latex"), Some(&cached("latex")));
+    }
+}
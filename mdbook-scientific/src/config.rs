@@ -0,0 +1,101 @@
+//! Helpers to extract values from the `[preprocessor.scientific]` section of `book.toml`.
+
+use crate::errors::{Result, ScientificError};
+use mdbook_boilerplate::Table;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Get a string value, if present.
+pub fn get_str<'a>(cfg: &'a Table, key: &str) -> Result<Option<&'a str>> {
+    cfg.get(key)
+        .map(|value| {
+            value
+                .as_str()
+                .ok_or_else(|| ScientificError::InvalidConfigValue {
+                    key: key.to_owned(),
+                    value: value.to_string(),
+                })
+        })
+        .transpose()
+}
+
+/// Get a list of strings, if present.
+pub fn get_str_list<'a>(cfg: &'a Table, key: &str) -> Result<Option<Vec<&'a str>>> {
+    cfg.get(key)
+        .map(|value| {
+            value
+                .as_array()
+                .and_then(|array| array.iter().map(|item| item.as_str()).collect())
+                .ok_or_else(|| ScientificError::InvalidConfigValue {
+                    key: key.to_owned(),
+                    value: value.to_string(),
+                })
+        })
+        .transpose()
+}
+
+/// Get a value that can be parsed from a string, i.e. one of the option enums.
+pub fn get_parsed<T>(cfg: &Table, key: &str) -> Result<Option<T>>
+where
+    T: FromStr<Err = ScientificError>,
+{
+    get_str(cfg, key)?.map(T::from_str).transpose()
+}
+
+/// Get a non-negative integer value, if present.
+pub fn get_integer(cfg: &Table, key: &str) -> Result<Option<usize>> {
+    cfg.get(key)
+        .map(|value| {
+            value
+                .as_integer()
+                .and_then(|integer| usize::try_from(integer).ok())
+                .ok_or_else(|| ScientificError::InvalidConfigValue {
+                    key: key.to_owned(),
+                    value: value.to_string(),
+                })
+        })
+        .transpose()
+}
+
+/// Get an integer value within `range`, if present.
+pub fn get_integer_in(
+    cfg: &Table,
+    key: &str,
+    range: RangeInclusive<usize>,
+) -> Result<Option<usize>> {
+    match get_integer(cfg, key)? {
+        Some(integer) if !range.contains(&integer) => Err(ScientificError::InvalidConfigValue {
+            key: key.to_owned(),
+            value: integer.to_string(),
+        }),
+        integer => Ok(integer),
+    }
+}
+
+/// Get a boolean value, if present.
+pub fn get_bool(cfg: &Table, key: &str) -> Result<Option<bool>> {
+    cfg.get(key)
+        .map(|value| {
+            value
+                .as_bool()
+                .ok_or_else(|| ScientificError::InvalidConfigValue {
+                    key: key.to_owned(),
+                    value: value.to_string(),
+                })
+        })
+        .transpose()
+}
+
+/// Get a nested table, i.e. `[preprocessor.scientific.<key>]`, if present.
+pub fn get_table<'a>(cfg: &'a Table, key: &str) -> Result<Option<&'a Table>> {
+    cfg.get(key)
+        .map(|value| {
+            value
+                .as_table()
+                .ok_or_else(|| ScientificError::InvalidConfigValue {
+                    key: key.to_owned(),
+                    value: value.to_string(),
+                })
+        })
+        .transpose()
+}
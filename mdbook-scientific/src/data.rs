@@ -0,0 +1,168 @@
+//! Data files read by `gnuplot` and python plots, resolved relative to the chapter.
+//!
+//! Any quoted relative or absolute file name with an extension that exists next to the chapter
+//! counts as data file, i.e. `plot 'measurements.csv'` or `np.loadtxt("data/run1.dat")`. Other
+//! literals shaped like file names, such as `rcParams["font.family"]`, are left alone.
+
+use fs_err as fs;
+use regex::{Captures, Regex};
+use std::path::{Path, PathBuf};
+
+use crate::errors::Result;
+use crate::fragments::hash;
+use crate::types::Origin;
+
+/// A single or double quoted file name
+fn file_name_literal() -> Regex {
+    Regex::new(r#"'([\w./-]*\.[A-Za-z]\w*)'|"([\w./-]*\.[A-Za-z]\w*)""#)
+        .expect("Regex is valid. qed")
+}
+
+/// A call reading the file name literal that follows
+fn reading_call() -> Regex {
+    Regex::new(r"(?:\b(?:s?plot|load)\s+|\b(?:open|loadtxt|genfromtxt|read_csv)\(\s*)$")
+        .expect("Regex is valid. qed")
+}
+
+/// The file name within the quotes
+fn file_name<'t>(captures: &Captures<'t>) -> regex::Match<'t> {
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .expect("One of the alternatives matched. qed")
+}
+
+/// A file referenced by a plot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataFile {
+    /// As written in the plot source
+    pub reference: String,
+    /// Canonical path of the file
+    pub path: PathBuf,
+    /// Hash over the contents of the file
+    pub hash: String,
+}
+
+/// Find and read all data files `source` references, relative to the chapter of `origin`.
+///
+/// `source` is the block content following the parameters of the opening delimiter. Missing
+/// files are only warned about when a plot or read call takes them, the tool reports the
+/// failure itself.
+pub fn find_data_files(source: &str, origin: &Origin) -> Result<Vec<DataFile>> {
+    let chapter_dir = origin
+        .chapter_path
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let reading_call = reading_call();
+    let mut files = Vec::<DataFile>::new();
+    for captures in file_name_literal().captures_iter(source) {
+        let name = file_name(&captures);
+        let reference = name.as_str();
+        if files.iter().any(|file| file.reference == reference) {
+            continue;
+        }
+        let path = match fs::canonicalize(chapter_dir.join(reference)) {
+            Ok(path) if path.is_file() => path,
+            _ => {
+                let quote = captures.get(0).expect("Whole match exists. qed").start();
+                let line_start = source[..quote].rfind('\n').map_or(0, |idx| idx + 1);
+                if reading_call.is_match(&source[line_start..quote]) {
+                    // the source starts on the line of the opening delimiter
+                    let lineno = origin.lineno + source[..quote].matches('\n').count();
+                    let column = source[line_start..name.start()].chars().count() + 1;
+                    log::warn!(
+                        "{}:{}:{}: Data file `{}` not found\n{}",
+                        origin.chapter_path.display(),
+                        lineno,
+                        column,
+                        chapter_dir.join(reference).display(),
+                        origin.snippet(lineno, column)
+                    );
+                }
+                continue;
+            }
+        };
+        let contents = fs::read(&path)?;
+        files.push(DataFile {
+            reference: reference.to_owned(),
+            path,
+            hash: hash(contents),
+        });
+    }
+    Ok(files)
+}
+
+/// Replace the references to data files in `source` by their canonical paths.
+pub fn resolve_data_files(source: &str, files: &[DataFile]) -> String {
+    file_name_literal()
+        .replace_all(source, |captures: &Captures| {
+            let reference = file_name(captures).as_str();
+            match files.iter().find(|file| file.reference == reference) {
+                Some(file) => format!("{0}{1}{0}", &captures[0][..1], file.path.display()),
+                None => captures[0].to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+
+    #[test]
+    fn data_files_are_relative_to_the_chapter() {
+        let dir = TempDir::new("data-files");
+        fs::create_dir_all(dir.join("src/plots")).unwrap();
+        fs::write(dir.join("src/plots/run1.csv"), "1,2\n2,4\n").unwrap();
+        let chapter = dir.join("src/plots/results.md");
+        let source = "\nset title \"Run 1\"\nplot 'run1.csv' using 1:2, \"missing.dat\"\n";
+        let origin = Origin {
+            chapter_path: chapter.clone(),
+            lineno: 7,
+            column: 1,
+            lines: vec![
+                "$$gnuplot, runs, Runs".to_owned(),
+                "set title \"Run 1\"".to_owned(),
+                "plot 'run1.csv' using 1:2, \"missing.dat\"".to_owned(),
+            ],
+        };
+
+        // a missing file is left to gnuplot
+        let files = find_data_files(source, &origin).unwrap();
+        assert_eq!(
+            Vec::from_iter(files.iter().map(|file| file.reference.as_str())),
+            ["run1.csv"]
+        );
+
+        let source = source.replace(", \"missing.dat\"", "");
+        let files = find_data_files(&source, &origin).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].reference, "run1.csv");
+        let path = fs::canonicalize(dir.join("src/plots/run1.csv")).unwrap();
+        assert_eq!(
+            resolve_data_files(&source, &files),
+            format!(
+                "\nset title \"Run 1\"\nplot '{}' using 1:2\n",
+                path.display()
+            )
+        );
+
+        fs::write(dir.join("src/plots/run1.csv"), "1,3\n").unwrap();
+        let changed = find_data_files(&source, &origin).unwrap();
+        assert_ne!(changed[0].hash, files[0].hash);
+    }
+
+    #[test]
+    fn other_literals_are_no_data_files() {
+        let origin = Origin {
+            chapter_path: PathBuf::from("src/plots.md"),
+            lineno: 3,
+            column: 1,
+            lines: Vec::new(),
+        };
+        let source = "\nplt.rcParams[\"font.family\"] = \"serif\"\nmpl.rcParams['lines.linewidth'] = 2\nplt.title(\"Rev.B\")\n";
+        assert_eq!(find_data_files(source, &origin).unwrap(), []);
+        assert_eq!(resolve_data_files(source, &[]), source);
+    }
+}
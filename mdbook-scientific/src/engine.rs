@@ -0,0 +1,184 @@
+//! The TeX engines fragments can be compiled with.
+//!
+//! `latex` produces a dvi, all others a pdf, both are converted to svg by `dvisvgm`.
+
+use std::path::Path;
+use std::process::{Command, Output};
+use std::str::FromStr;
+
+use mdbook_boilerplate::find_program;
+
+use crate::errors::{Result, ScientificError};
+use crate::runner::Runner;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TexEngine {
+    /// `latex` followed by `dvisvgm`
+    #[default]
+    LatexDvisvgm,
+    PdfLatex,
+    XeLatex,
+    LuaLatex,
+    /// Self-contained engine, as used by the `mdbook-tectonic` renderer
+    Tectonic,
+}
+
+impl TexEngine {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LatexDvisvgm => "latex+dvisvgm",
+            Self::PdfLatex => "pdflatex",
+            Self::XeLatex => "xelatex",
+            Self::LuaLatex => "lualatex",
+            Self::Tectonic => "tectonic",
+        }
+    }
+
+    /// The external tools involved, the engine itself and the svg conversion.
+    pub fn tools(&self) -> &'static [&'static str] {
+        match self {
+            Self::LatexDvisvgm => &["latex", "dvisvgm"],
+            Self::PdfLatex => &["pdflatex", "dvisvgm"],
+            Self::XeLatex => &["xelatex", "dvisvgm"],
+            Self::LuaLatex => &["lualatex", "dvisvgm"],
+            Self::Tectonic => &["tectonic", "dvisvgm"],
+        }
+    }
+
+    /// Supports system fonts via `fontspec` and `unicode-math`
+    pub fn is_unicode(&self) -> bool {
+        matches!(self, Self::XeLatex | Self::LuaLatex | Self::Tectonic)
+    }
+
+    /// Extension of the compiled document, next to the `.tex` file
+    pub fn output_extension(&self) -> &'static str {
+        match self {
+            Self::LatexDvisvgm => "dvi",
+            _ => "pdf",
+        }
+    }
+
+    /// The gnuplot terminal producing a standalone document this engine can compile.
+    pub fn gnuplot_terminal(&self) -> &'static str {
+        match self {
+            Self::LatexDvisvgm => "epslatex color standalone",
+            // eps graphics can not be included by pdf producing engines
+            _ => "cairolatex pdf color standalone",
+        }
+    }
+
+    /// Compile `tex_file` within `dir`, regardless of the exit status.
+    ///
+    /// Returns the command for error reporting along with its output.
+    pub fn compile(
+        &self,
+        dir: &Path,
+        tex_file: &Path,
+        runner: &Runner,
+    ) -> Result<(Command, Output)> {
+        let program = self.tools()[0];
+        let mut command = Command::new(find_program(program)?);
+        command.current_dir(dir);
+        match self {
+            Self::Tectonic => {
+                command.args(["--outfmt=pdf", "--chatter=minimal"]);
+            }
+            _ => {
+                // never wait for input on errors
+                command.arg("-interaction=nonstopmode");
+            }
+        }
+        command.arg(tex_file);
+        let output = runner.run(&mut command, None)?;
+        Ok((command, output))
+    }
+
+    /// Extract the first error as message, context and line of the `.tex` file.
+    ///
+    /// The latex family prints `! msg` and `l.<line> <context>` to stdout, tectonic
+    /// `error: <file>:<line>: msg` to stderr.
+    pub fn parse_error(&self, output: &Output) -> Option<(String, String, usize)> {
+        match self {
+            Self::Tectonic => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let line = stderr
+                    .lines()
+                    .find_map(|line| line.strip_prefix("error: "))?;
+                let mut elms = line.splitn(3, ':').map(str::trim);
+                let (_file, lineno, msg) = (elms.next()?, elms.next()?, elms.next()?);
+                Some((msg.to_owned(), String::new(), lineno.parse().ok()?))
+            }
+            _ => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                if stdout.trim().is_empty() {
+                    return None;
+                }
+                let err = stdout
+                    .split('\n')
+                    .filter(|x| {
+                        (x.starts_with("! ") || x.starts_with("l."))
+                            && !x.contains("Emergency stop")
+                    })
+                    .fold(
+                        (String::new(), String::new(), usize::MAX),
+                        |mut err, elm| {
+                            if let Some(striped) = elm.strip_prefix("! ") {
+                                err.0 = striped.to_owned();
+                            } else if let Some(striped) = elm.strip_prefix("l.") {
+                                let mut elms = striped.splitn(2, ' ').map(|x| x.trim());
+                                if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
+                                    err.2 = val;
+                                }
+                                if let Some(val) = elms.next() {
+                                    err.1 = val.to_owned();
+                                }
+                            }
+                            err
+                        },
+                    );
+                Some(err)
+            }
+        }
+    }
+}
+
+impl FromStr for TexEngine {
+    type Err = ScientificError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "latex+dvisvgm" | "latex" => Self::LatexDvisvgm,
+            "pdflatex" => Self::PdfLatex,
+            "xelatex" => Self::XeLatex,
+            "lualatex" => Self::LuaLatex,
+            "tectonic" => Self::Tectonic,
+            s => {
+                return Err(ScientificError::InvalidConfigValue {
+                    key: "engine".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    #[test]
+    fn tectonic_errors_are_parsed() {
+        let output = Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: b"note: Running TeX ...\nerror: scientific_1__abc.tex:12: Undefined control sequence\n"
+                .to_vec(),
+        };
+        assert_eq!(
+            TexEngine::Tectonic.parse_error(&output),
+            Some(("Undefined control sequence".to_owned(), String::new(), 12))
+        );
+    }
+}
@@ -1,4 +1,9 @@
+use itertools::Itertools;
 use nom_bibtex::error::BibtexError;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::types::Origin;
 
 pub type Result<T> = std::result::Result<T, ScientificError>;
 
@@ -19,8 +24,36 @@ pub enum ScientificError {
     #[error("Invalid math: {0} {1} at line {2}")]
     InvalidMath(String, String, usize),
 
-    #[error("Invalid reference to `{to}` in line no. {lineno}")]
-    InvalidReference { to: String, lineno: usize },
+    #[error("Fragment template {path} {msg}")]
+    InvalidTemplate { path: String, msg: String },
+
+    #[error("Font settings require `xelatex`, `lualatex` or `tectonic` as engine, not `{0}`")]
+    FontsRequireUnicodeEngine(String),
+
+    #[error("{}:{lineno}:{column}: {msg}\n{snippet}", .chapter_path.display())]
+    InvalidMathAt {
+        chapter_path: PathBuf,
+        lineno: usize,
+        column: usize,
+        msg: String,
+        snippet: String,
+    },
+
+    #[error("Failed to convert equation in line no. {lineno} to MathML: {msg}")]
+    InvalidMathMl { msg: String, lineno: usize },
+
+    #[error(
+        "Invalid reference to `{to}` in line no. {lineno}{}",
+        .did_you_mean.as_ref().map(|label| format!(", did you mean `{}`?", label)).unwrap_or_default()
+    )]
+    InvalidReference {
+        to: String,
+        lineno: usize,
+        did_you_mean: Option<String>,
+    },
+
+    #[error("Label `{label}` is defined already at {first}")]
+    DuplicateLabel { label: String, first: String },
 
     #[error("Unknown reference to `{kind}` in line no. {lineno}")]
     UnknownReferenceKind { kind: String, lineno: usize },
@@ -40,6 +73,9 @@ pub enum ScientificError {
     #[error("Key section not found")]
     KeySectionNotFound,
 
+    #[error("Invalid value `{value}` for config key `{key}`")]
+    InvalidConfigValue { key: String, value: String },
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
@@ -60,4 +96,53 @@ pub enum ScientificError {
 
     #[error(transparent)]
     Boilerplate(#[from] mdbook_boilerplate::Error),
+
+    #[error("`{cmd}` could not be started: {source}")]
+    ToolSpawn { cmd: String, source: std::io::Error },
+
+    #[error("`{cmd}` did not finish within {timeout:?}")]
+    ToolTimeout {
+        cmd: String,
+        timeout: std::time::Duration,
+    },
+
+    #[error("`{cmd}` failed ({status}):\n{output}")]
+    ToolFailed {
+        cmd: String,
+        status: std::process::ExitStatus,
+        output: String,
+    },
+
+    #[error("Fragment failed to render")]
+    FragmentFailed,
+
+    #[error("Failed to process {} item(s):\n{}", .0.len(), .0.iter().join("\n"))]
+    ItemsFailed(Vec<ItemError>),
+}
+
+/// An equation, figure or reference that failed, with its location in the book source
+///
+/// Failed fragments are reported once, at their first occurrence.
+#[derive(Debug)]
+pub struct ItemError {
+    pub origin: Origin,
+    pub source: ScientificError,
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            // already carries the exact location
+            ScientificError::InvalidMathAt { .. } => {
+                write!(f, "{}", self.source)
+            }
+            _ => write!(f, "{}: {}", self.origin, self.source),
+        }
+    }
+}
+
+impl std::error::Error for ItemError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
 }
@@ -1,176 +1,553 @@
 use fs_err as fs;
-use itertools::Itertools;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::{io::Write, str, usize};
 
+use latex2mathml::{latex_to_mathml, DisplayStyle};
 use mdbook_boilerplate::find_program;
+use regex::Regex;
 use sha2::{Digest, Sha256};
 
+use crate::data::{self, DataFile};
+use crate::engine::TexEngine;
 use crate::errors::*;
+use crate::preprocess::Settings;
+use crate::runner::{self, Runner};
+use crate::svg::{self, SvgMetrics, SvgOptions};
+use crate::template::FragmentTemplate;
 use crate::types::*;
 
-/// Convert input string to 24 character hash
-pub fn hash(input: impl AsRef<str>) -> String {
+/// Convert input string or bytes to 24 character hash
+pub fn hash(input: impl AsRef<[u8]>) -> String {
     let mut sh = Sha256::new();
-    sh.update(input.as_ref().as_bytes());
+    sh.update(input.as_ref());
     let mut out = format!("{:x}", sh.finalize());
     out.truncate(24);
     out
 }
 
-/// Generate SVG file from latex file with given zoom
+/// Relates the lines of a generated `.tex` file to the chapter source they stem from
+#[derive(Debug, Clone, Copy)]
+pub struct SourceMap<'a> {
+    pub origin: &'a Origin,
+    /// Line of the `.tex` file holding the first line of the fragment source, base 1
+    pub first_line: usize,
+    /// Text preceding the fragment source on `first_line`
+    pub prefix: &'a str,
+}
+
+impl SourceMap<'_> {
+    /// Locate a latex error, given the line number and context of its `l.` line.
+    ///
+    /// Latex prints the input up to the offending token as context, so the error is at
+    /// the last control sequence or the end of the context.
+    pub fn locate(&self, msg: &str, context: &str, tex_line: usize) -> ScientificError {
+        let offset = tex_line.saturating_sub(self.first_line);
+        let lineno = self.origin.lineno + offset;
+        let line = self
+            .origin
+            .lines
+            .get(offset)
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        let context = context.trim_start_matches("...");
+        let context = if offset == 0 {
+            context.strip_prefix(self.prefix).unwrap_or(context)
+        } else {
+            context
+        };
+        let token = context
+            .rfind('\\')
+            .map(|idx| &context[idx..])
+            .filter(|token| token.len() > 1 && token[1..].chars().all(char::is_alphabetic))
+            .unwrap_or_default();
+
+        let column = match line.rfind(context).filter(|_| !context.is_empty()) {
+            Some(idx) => line[..idx + context.len() - token.len()].chars().count() + 1,
+            None if offset == 0 => self.origin.column,
+            None => 1,
+        };
+
+        let msg = msg.trim_end_matches('.');
+        let msg = if token.is_empty() {
+            msg.to_owned()
+        } else {
+            format!("{} `{}`", msg, token)
+        };
+
+        ScientificError::InvalidMathAt {
+            chapter_path: self.origin.chapter_path.clone(),
+            lineno,
+            column,
+            msg,
+            snippet: self.origin.snippet(lineno, column),
+        }
+    }
+}
+
+/// Generate SVG file from latex file with given options
 ///
-/// `base` is used as based and added with extensions for intermediate files
-pub fn generate_svg_from_latex(base: &Path, zoom: f32) -> Result<PathBuf> {
+/// `base` is used as based and added with extensions for intermediate files, errors are
+/// reported relative to the chapter source if a `map` is given. The extents of the equation
+/// are stored next to the svg, if `dvisvgm` reports them.
+pub fn generate_svg_from_latex(
+    base: &Path,
+    options: SvgOptions,
+    map: Option<SourceMap<'_>>,
+    engine: TexEngine,
+    runner: &Runner,
+) -> Result<PathBuf> {
     let dest_path = base.parent().expect("Parent path must exist. qed");
     let file: &Path = base.file_name().unwrap().as_ref();
 
-    // use latex to generate a dvi
-    let dvi_path = base.with_extension("dvi");
-    if !dvi_path.exists() {
-        let latex_path = find_program("latex")?;
-
-        let cmd = Command::new(latex_path)
-            .current_dir(dest_path)
-            //.arg("--jobname").arg(&dvi_path)
-            .arg(&file.with_extension("tex"))
-            .output()?;
-
-        if !cmd.status.success() {
-            let buf = String::from_utf8_lossy(&cmd.stdout);
-
-            // latex prints error to the stdout, if this is empty, then something is fundamentally
-            // wrong with the latex binary (for example shared library error). In this case just
-            // exit the program
-            if buf.is_empty() {
-                let buf = String::from_utf8_lossy(&cmd.stderr);
-                panic!("latex exited with `{}`", buf);
-            }
-
-            let err = buf
-                .split('\n')
-                .filter(|x| {
-                    (x.starts_with("! ") || x.starts_with("l.")) && !x.contains("Emergency stop")
-                })
-                .fold(("", "", usize::MAX), |mut err, elm| {
-                    if let Some(striped) = elm.strip_prefix("! ") {
-                        err.0 = striped;
-                    } else if let Some(striped) = elm.strip_prefix("l.") {
-                        let mut elms = striped.splitn(2, ' ').map(|x| x.trim());
-                        if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
-                            err.2 = val;
-                        }
-                        if let Some(val) = elms.next() {
-                            err.1 = val;
-                        }
-                    }
-
-                    err
-                });
-
-            return Err(ScientificError::InvalidMath(
-                err.0.to_string(),
-                err.1.to_string(),
-                err.2,
-            ));
+    // use the engine to generate a dvi or pdf
+    let compiled_path = base.with_extension(engine.output_extension());
+    if !compiled_path.exists() {
+        let (command, output) = engine.compile(dest_path, &file.with_extension("tex"), runner)?;
+
+        if !output.status.success() {
+            // errors are printed to stdout or stderr, if there is none something is fundamentally
+            // wrong with the binary (for example shared library error)
+            let (msg, context, line) = match engine.parse_error(&output) {
+                Some(err) => err,
+                None => return Err(runner::failed(&command, &output)),
+            };
+
+            return Err(match map {
+                Some(map) if line != usize::MAX => map.locate(&msg, &context, line),
+                _ => ScientificError::InvalidMath(msg, context, line),
+            });
         }
     }
 
-    // convert the dvi to a svg file with the woff font format
+    // convert the dvi or pdf to a svg file, fonts in the woff format or as paths
     let svg_path = base.with_extension("svg");
-    if !svg_path.exists() && dvi_path.exists() {
+    if !svg_path.exists() && compiled_path.exists() {
         let dvisvgm_path = find_program("dvisvgm")?;
 
-        let cmd = Command::new(dvisvgm_path)
+        let mut command = Command::new(dvisvgm_path);
+        command
             .current_dir(dest_path)
-            .arg("-b")
-            .arg("1")
-            .arg("--font-format=woff")
-            .arg(&format!("--zoom={}", zoom))
-            .arg(&dvi_path)
-            .output()?;
+            .args(dvisvgm_args(engine.output_extension() == "pdf", options))
+            .arg(&compiled_path);
+        let cmd = runner.run(&mut command, None)?;
 
         let buf = String::from_utf8_lossy(&cmd.stderr);
         if !cmd.status.success() || buf.contains("error:") {
             return Err(ScientificError::InvalidDvisvgm(buf.to_string()));
         }
+        if let Some(metrics) = SvgMetrics::from_dvisvgm(&buf) {
+            fs::write(metrics_file(base), serde_json::to_string(&metrics)?)?;
+        }
     }
 
     Ok(svg_path)
 }
 
+/// Options of `dvisvgm`, the font handling applies to dvi and pdf input alike.
+fn dvisvgm_args(pdf: bool, options: SvgOptions) -> Vec<String> {
+    let mut args = vec!["-b".to_owned(), "1".to_owned()];
+    if pdf {
+        args.push("--pdf".to_owned());
+    }
+    if options.fonts {
+        args.push("--font-format=woff".to_owned());
+    } else {
+        args.push("--no-fonts".to_owned());
+    }
+    args.push(format!("--zoom={}", options.zoom));
+    args
+}
+
+/// Sidecar of a fragment holding its [`SvgMetrics`]
+fn metrics_file(fragment_file: &Path) -> PathBuf {
+    fragment_file.with_extension("metrics.json")
+}
+
 /// Generate latex file from gnuplot
 ///
-/// This function generates a latex file with gnuplot `epslatex` backend and then source it into
-/// the generate latex function
-fn generate_latex_from_gnuplot<'a>(
+/// This function generates a latex file with the gnuplot `epslatex` or `cairolatex` backend,
+/// as suitable for the engine, and then source it into the generate latex function
+fn generate_latex_from_gnuplot(
     dest_dir: &Path,
-    content: &Content<'a>,
+    content: &str,
     filename: &Path,
+    engine: TexEngine,
+    runner: &Runner,
 ) -> Result<()> {
-    let content = content.trimmed().as_str();
     let gnuplot_path = find_program("gnuplot")?;
 
-    let cmd = Command::new(gnuplot_path)
-        .stdin(Stdio::piped())
-        .current_dir(dest_dir)
-        .arg("-p")
-        .spawn()?;
+    let input = format!(
+        "set output '{}'\nset terminal {}\n{}",
+        filename.display(),
+        engine.gnuplot_terminal(),
+        content
+    );
+    runner.run_checked(
+        Command::new(gnuplot_path).current_dir(dest_dir),
+        Some(input.as_bytes()),
+    )?;
+
+    Ok(())
+}
+
+/// A unit of work for the external tools, unique by its `fragment_file`.
+///
+/// Collected from all chapters first, rendered in parallel and substituted afterwards.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub kind: EquBlockKind,
+    pub zoom: f32,
+    /// Equation, latex or gnuplot source without delimiters
+    pub source: String,
+    /// The rendered svg or png within the fragment path
+    pub fragment_file: PathBuf,
+    /// The svg or png within the asset path, as referenced from the output
+    pub asset_file: PathBuf,
+    /// The first occurrence, for error reporting
+    pub origin: Origin,
+    /// Compiles the generated latex, unused for `gnuplotonly`
+    pub engine: TexEngine,
+    /// Document equations are spliced into, unused for figures
+    pub template: FragmentTemplate,
+    /// Svg markup is inlined into html, instead of referencing the file
+    pub embedding: SvgEmbedding,
+    /// Inlined svgs use `currentColor` instead of black
+    pub follow_text_color: bool,
+    /// Rasterize the svg to png at this resolution
+    pub raster_dpi: Option<u32>,
+    /// Files read by a plot, relative to the chapter
+    pub data: Vec<DataFile>,
+}
+
+impl Fragment {
+    /// Determine the fragment files for the given content, without rendering anything.
+    ///
+    /// Fragments are named by their inputs alone, so the same equation in several chapters is
+    /// rendered once and renumbering chapters does not rename it.
+    pub fn new(
+        settings: &Settings<'_>,
+        content: &Content<'_>,
+        kind: EquBlockKind,
+        zoom: f32,
+        origin: Origin,
+    ) -> Result<Self> {
+        let source = content.trimmed().as_str();
+        let data = match kind {
+            EquBlockKind::GnuPlot | EquBlockKind::GnuPlotOnly | EquBlockKind::Python => {
+                data::find_data_files(source, &origin)?
+            }
+            EquBlockKind::Equation | EquBlockKind::Latex => Vec::new(),
+        };
+        let mut fragment = Self {
+            kind,
+            zoom,
+            source: source.to_owned(),
+            fragment_file: PathBuf::new(),
+            asset_file: PathBuf::new(),
+            origin,
+            engine: settings.engine,
+            template: settings.template.clone(),
+            embedding: settings.svg_embedding,
+            follow_text_color: settings.svg_current_color,
+            raster_dpi: settings.raster_dpi,
+            data,
+        };
+        // named by the same inputs as the output, the same plot of another chapter reads
+        // other files though, named relative to the book so moving it renames nothing
+        let data_paths = Vec::from_iter(fragment.data.iter().map(|file| {
+            let path = file.path.strip_prefix(settings.root).unwrap_or(&file.path);
+            path.display().to_string()
+        }));
+        let name = format!(
+            "scientific_{}",
+            fragment.hash_with(data_paths.iter().map(String::as_str))
+        );
+        let name = match settings.raster_dpi {
+            // distinct stem, the outputs of the svg variant must not count as its own
+            Some(dpi) => PathBuf::from(format!("{}_{}dpi", name, dpi)).with_extension("png"),
+            // latex renderers include the plot as pgf, to typeset its text with the book,
+            // distinct stem as well
+            None if kind == EquBlockKind::Python && !settings.renderer.is_html_like() => {
+                PathBuf::from(format!("{}_pgf", name)).with_extension("pgf")
+            }
+            None => PathBuf::from(name).with_extension("svg"),
+        };
+        fragment.fragment_file = settings.fragment_path.join(&name);
+        fragment.asset_file = settings.asset_path.join(&name);
+
+        if content.byte_range.len() == 2 {
+            log::error!(
+                "Found $$ but got interpreted as two consecutive $ signs! {:?}",
+                &content.byte_range
+            )
+        }
+
+        Ok(fragment)
+    }
+
+    /// Name of the `fragment_file`, the key of the fragment store
+    pub fn store_name(&self) -> String {
+        self.fragment_file
+            .file_name()
+            .expect("Fragment file always has a name. qed")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// The rendered svg, the `fragment_file` itself unless rasterized
+    pub fn svg_file(&self) -> PathBuf {
+        self.fragment_file.with_extension("svg")
+    }
+
+    /// A python plot saved by the pgf backend, instead of an svg
+    fn is_pgf(&self) -> bool {
+        self.fragment_file.extension() == Some("pgf".as_ref())
+    }
+
+    /// The external tools involved in rendering.
+    pub fn tools(&self) -> Vec<&'static str> {
+        let mut tools = match self.kind {
+            EquBlockKind::Equation | EquBlockKind::Latex => self.engine.tools().to_vec(),
+            EquBlockKind::GnuPlot => [&["gnuplot"], self.engine.tools()].concat(),
+            EquBlockKind::GnuPlotOnly => vec!["gnuplot"],
+            EquBlockKind::Python => vec!["python3"],
+        };
+        if self.raster_dpi.is_some() {
+            tools.push("rsvg-convert");
+        }
+        tools
+    }
+
+    /// Hash over everything that determines the rendered output, besides the tool versions.
+    pub fn input_hash(&self) -> String {
+        self.hash_with(self.data.iter().map(|file| file.hash.as_str()))
+    }
+
+    /// Hash over the inputs of the output, with `data` standing in for the data files.
+    fn hash_with<'d>(&self, data: impl IntoIterator<Item = &'d str>) -> String {
+        let template = match self.kind {
+            EquBlockKind::Equation => self.template.as_str(),
+            _ => "",
+        };
+        let (engine, embedding) = match self.kind {
+            EquBlockKind::GnuPlotOnly | EquBlockKind::Python => ("", ""),
+            _ => (self.engine.as_str(), self.embedding.as_str()),
+        };
+        let data = Vec::from_iter(data);
+        hash(format!(
+            "{}\0{}\0{}\0{}\0{}\0{}\0{}",
+            self.kind.as_desc(),
+            self.zoom,
+            engine,
+            embedding,
+            template,
+            self.source,
+            data.join("\0")
+        ))
+    }
+
+    /// Inlined svgs can not refer to fonts by name, since they'd clash within a page,
+    /// rasterized ones as the fonts are not available to `rsvg-convert`.
+    fn svg_options(&self) -> SvgOptions {
+        SvgOptions {
+            zoom: self.zoom,
+            fonts: self.embedding == SvgEmbedding::Object && self.raster_dpi.is_none(),
+        }
+    }
 
-    let mut stdin = cmd.stdin.expect("Stdin of gnuplot spawn must exist. qed");
+    /// Run the external tools to create the `fragment_file`.
+    pub fn render(&self, runner: &Runner) -> Result<()> {
+        let (emoji, desc) = self.kind.as_emoji_w_desc();
+        log::info!("{emoji} Rendering {desc} from {}", self.origin);
+        let fragment_dir = self
+            .fragment_file
+            .parent()
+            .expect("Fragment file is always joined to the fragment path. qed");
+        let svg_file = self.svg_file();
+        let source = data::resolve_data_files(&self.source, &self.data);
+        match self.kind {
+            EquBlockKind::Equation => generate_replacement_file_from_template(
+                &svg_file,
+                &self.source,
+                self.svg_options(),
+                Some(&self.origin),
+                &self.template,
+                self.engine,
+                runner,
+            )?,
+            EquBlockKind::Latex => parse_latex(
+                &svg_file,
+                &self.source,
+                self.svg_options(),
+                Some(&self.origin),
+                self.engine,
+                runner,
+            )?,
+            EquBlockKind::GnuPlot => parse_gnuplot(
+                fragment_dir,
+                &svg_file,
+                &source,
+                self.svg_options(),
+                self.engine,
+                runner,
+            )?,
+            EquBlockKind::GnuPlotOnly => {
+                parse_gnuplot_only(fragment_dir, &svg_file, &source, runner)?
+            }
+            EquBlockKind::Python => {
+                let output = if self.is_pgf() {
+                    &self.fragment_file
+                } else {
+                    &svg_file
+                };
+                parse_python(fragment_dir, output, &source, &self.origin, runner)?
+            }
+        };
+        if let Some(dpi) = self.raster_dpi {
+            // the png is shown at the size of the unzoomed svg
+            rasterize(
+                &svg_file,
+                &self.fragment_file,
+                dpi as f32 / self.zoom,
+                runner,
+            )?;
+        }
+        Ok(())
+    }
 
-    stdin.write_all(format!("set output '{}'\n", filename.display()).as_bytes())?;
-    stdin.write_all("set terminal epslatex color standalone\n".as_bytes())?;
-    stdin.write_all(content.as_bytes())?;
+    /// Create the replacement for a content once rendered.
+    pub fn replacement<'a>(&self, content: &Content<'a>) -> Result<Replacement<'a>> {
+        let intermediate = match self.kind {
+            EquBlockKind::GnuPlot => Some(fs::read_to_string(
+                self.fragment_file.with_extension("tex"),
+            )?),
+            EquBlockKind::Python if self.is_pgf() => Some(fs::read_to_string(&self.fragment_file)?),
+            _ => None,
+        };
+        let metrics = || {
+            fs::read_to_string(metrics_file(&self.svg_file()))
+                .ok()
+                .and_then(|json| serde_json::from_str::<SvgMetrics>(&json).ok())
+        };
+        let rendered = match self.embedding {
+            _ if self.raster_dpi.is_some() => {
+                let svg = fs::read_to_string(self.svg_file())?;
+                Rendered::Raster {
+                    fragment_file: self.fragment_file.clone(),
+                    asset_file: self.asset_file.clone(),
+                    extents: svg::extents(&svg, self.zoom, metrics().as_ref()),
+                }
+            }
+            // only the intermediate is used
+            _ if self.is_pgf() => {
+                Rendered::svg(self.fragment_file.clone(), self.asset_file.clone())
+            }
+            SvgEmbedding::Object => {
+                Rendered::svg(self.fragment_file.clone(), self.asset_file.clone())
+            }
+            SvgEmbedding::Inline => {
+                let svg = fs::read_to_string(&self.fragment_file)?;
+                let metrics = metrics();
+                let id_prefix = self
+                    .fragment_file
+                    .file_stem()
+                    .expect("Fragment file always has a name. qed")
+                    .to_string_lossy();
+                Rendered::InlineSvg(svg::inline(
+                    &svg,
+                    &id_prefix,
+                    self.zoom,
+                    metrics.as_ref(),
+                    self.follow_text_color,
+                ))
+            }
+        };
+        Ok(Replacement {
+            content: content.clone(),
+            intermediate,
+            rendered,
+            fallback: None,
+        })
+    }
+}
 
+/// Rasterize an svg to png with `rsvg-convert`, at `dpi` pixels per inch of the svg size.
+fn rasterize(svg: &Path, png: &Path, dpi: f32, runner: &Runner) -> Result<()> {
+    let mut command = Command::new(find_program("rsvg-convert")?);
+    command
+        .arg(format!("--dpi-x={}", dpi))
+        .arg(format!("--dpi-y={}", dpi))
+        .args(["--format=png", "--output"])
+        .arg(png)
+        .arg(svg);
+    runner.run_checked(&mut command, None)?;
     Ok(())
 }
 
-/// Parse an equation with the given zoom
-pub fn generate_replacement_file_from_template<'a>(
-    fragment_path: &Path,
-    asset_path: &Path,
-    content: &Content<'a>,
-    zoom: f32,
-    chapter_number: &str,
-    _chapter_name: &str,
-) -> Result<Replacement<'a>> {
-    let content_hash = hash(content.as_str());
-    let name = format!(
-        "scientific_{}__{}",
-        &chapter_number,
-        &content_hash.as_str()[..10]
-    )
-    .replace('.', "_");
-    let name = PathBuf::from(name).with_extension("svg");
-
-    let fragment_file = fragment_path.join(&name);
-    let svg_asset_file = asset_path.join(&name);
-
-    if content.byte_range.len() == 2 {
-        log::error!(
-            "Found $$ but got interpreted as two consecutive $ signs! {:?}",
-            &content.byte_range
-        )
-    }
+/// Render all fragments with `jobs` worker threads.
+///
+/// Every fragment is attempted, the failed ones are returned ordered by their origin.
+pub fn render_all<'f>(
+    fragments: &[&'f Fragment],
+    jobs: usize,
+    runner: &Runner,
+) -> Vec<(&'f Fragment, ScientificError)> {
+    let jobs = jobs.clamp(1, fragments.len().max(1));
     log::info!(
-        r#"Found equation from {}:{}..{}:{}:
-    {}"#,
-        content.start.lineno,
-        content.start.column,
-        content.end.lineno,
-        content.end.column,
-        content.s
+        "Rendering {} fragments with {} worker(s)",
+        fragments.len(),
+        jobs
     );
+
+    let next = AtomicUsize::new(0);
+    let failed = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while let Some(&fragment) = fragments.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let Err(source) = fragment.render(runner) {
+                        log::error!(
+                            "Failed to render fragment from {}: {}",
+                            fragment.origin,
+                            source
+                        );
+                        failed
+                            .lock()
+                            .expect("Worker threads do not panic while holding the lock. qed")
+                            .push((fragment, source));
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failed = failed
+        .into_inner()
+        .expect("All worker threads are joined. qed");
+    failed.sort_by(|(a, _), (b, _)| a.origin.cmp(&b.origin));
+    failed
+}
+
+/// Write the equation into the template and render it with the given options
+pub fn generate_replacement_file_from_template(
+    fragment_file: &Path,
+    tex: &str,
+    options: SvgOptions,
+    origin: Option<&Origin>,
+    template: &FragmentTemplate,
+    engine: TexEngine,
+    runner: &Runner,
+) -> Result<PathBuf> {
     log::debug!(
         "Using temporary helper file {}",
         fragment_file.with_extension("tex").display()
     );
 
-    let tex = content.trimmed().as_str();
-
     // create a new tex file containing the equation
     let mut file = fs::OpenOptions::new()
         .create(true)
@@ -178,20 +555,17 @@ pub fn generate_replacement_file_from_template<'a>(
         .write(true)
         .open(fragment_file.with_extension("tex"))?;
 
-    let fragment = include_str!("fragment.tex")
-        .split("$$")
-        .enumerate()
-        .map(|(idx, s)| match idx {
-            0 | 2 => s,
-            1 => tex,
-            _ => unreachable!("fragment.tex must have exactly 2 instances of `$$`"),
-        })
-        .join("$$");
+    let map = origin.map(|origin| SourceMap {
+        origin,
+        first_line: template.equation_line(),
+        prefix: "$$",
+    });
+    let fragment = template.splice(tex);
 
     let bytes = fragment.as_bytes();
     file.write_all(fragment.as_bytes())?;
 
-    let svg_fragment_file = generate_svg_from_latex(&fragment_file, zoom)?;
+    let svg_fragment_file = generate_svg_from_latex(fragment_file, options, map, engine, runner)?;
     log::debug!(
         "Wrote fragment with {} bytes to {}",
         bytes.len(),
@@ -205,26 +579,18 @@ pub fn generate_replacement_file_from_template<'a>(
         svg_fragment_file.display()
     );
 
-    Ok(Replacement {
-        content: content.clone(),
-        intermediate: None,
-        svg_fragment_file,
-        svg_asset_file,
-    })
+    Ok(svg_fragment_file)
 }
 
 /// Parse a latex content and convert it to a SVG file
-pub fn parse_latex<'a>(
-    fragment_path: &Path,
-    asset_path: &Path,
-    content: &Content<'a>,
-) -> Result<Replacement<'a>> {
-    let tex = content.trimmed().as_str();
-    let name = hash(tex);
-    let name = PathBuf::from(name).with_extension("svg");
-    let svg_fragment_file = fragment_path.join(&name);
-    let svg_asset_file = asset_path.join(&name);
-
+pub fn parse_latex(
+    svg_fragment_file: &Path,
+    tex: &str,
+    options: SvgOptions,
+    origin: Option<&Origin>,
+    engine: TexEngine,
+    runner: &Runner,
+) -> Result<PathBuf> {
     // create a new tex file containing the equation
     if !svg_fragment_file.with_extension("tex").exists() {
         let mut file = fs::OpenOptions::new()
@@ -236,92 +602,187 @@ pub fn parse_latex<'a>(
         file.write_all(tex.as_bytes())?;
     }
 
-    let svg_fragment_file = generate_svg_from_latex(&svg_fragment_file, 1.0)?;
-
-    Ok(Replacement {
-        content: content.clone(),
-        intermediate: None,
-        svg_fragment_file,
-        svg_asset_file,
-    })
+    let map = origin.map(|origin| SourceMap {
+        origin,
+        first_line: 1,
+        prefix: "",
+    });
+    generate_svg_from_latex(svg_fragment_file, options, map, engine, runner)
 }
 
 /// Parse a gnuplot file and generate a SVG file
-pub fn parse_gnuplot<'a>(
+pub fn parse_gnuplot(
     fragment_path: &Path,
-    asset_path: &Path,
-    content: &Content<'a>,
-) -> Result<Replacement<'a>> {
-    let name = hash(content.as_str());
-    let name = PathBuf::from(name).with_extension("svg");
-
-    let path = fragment_path.join(&name);
+    path: &Path,
+    gnuplot_input: &str,
+    options: SvgOptions,
+    engine: TexEngine,
+    runner: &Runner,
+) -> Result<PathBuf> {
     if !path.with_extension("tex").exists() {
         //let name_plot = format!("{}_plot", name);
-        generate_latex_from_gnuplot(fragment_path, content, path.with_extension("tex").as_path())?;
+        generate_latex_from_gnuplot(
+            fragment_path,
+            gnuplot_input,
+            path.with_extension("tex").as_path(),
+            engine,
+            runner,
+        )?;
     }
 
-    let svg_asset_path = asset_path.join(&name);
-
-    let svg_fragment_path = generate_svg_from_latex(&path, 1.0)?;
-
-    let intermediate = fs::read_to_string(path.with_extension("tex"))?;
-
-    Ok(Replacement {
-        content: content.to_owned(),
-        intermediate: Some(intermediate),
-        svg_fragment_file: svg_fragment_path,
-        svg_asset_file: svg_asset_path,
-    })
+    // the latex is generated by gnuplot, its lines do not relate to the chapter
+    generate_svg_from_latex(path, options, None, engine, runner)
 }
 
 /// Parse gnuplot without using the latex backend
-pub fn parse_gnuplot_only<'a>(
+pub fn parse_gnuplot_only(
     fragment_path: &Path,
-    asset_path: &Path,
-    content: &Content<'a>,
-) -> Result<Replacement<'a>> {
-    let gnuplot_input = content.trimmed().as_str();
-    let name = hash(gnuplot_input);
-    let name = PathBuf::from(name).with_extension("svg");
-    let svg_fragment_path = fragment_path.join(&name);
-    let svg_asset_path = asset_path.join(&name);
+    svg_fragment_path: &Path,
+    gnuplot_input: &str,
+    runner: &Runner,
+) -> Result<PathBuf> {
+    let name = svg_fragment_path
+        .file_name()
+        .expect("Fragment file always has a name. qed");
 
     if !svg_fragment_path.with_extension("svg").exists() {
         let gnuplot_path = find_program("gnuplot")?;
-        let cmd = Command::new(gnuplot_path)
-            .stdin(Stdio::piped())
-            .current_dir(fragment_path)
-            .arg("-p")
-            .spawn()?;
-
-        let mut stdin = cmd.stdin.unwrap();
-        stdin.write_all(format!("set output '{}'\n", name.display()).as_bytes())?;
-        stdin.write_all("set terminal svg\n".as_bytes())?;
-        stdin.write_all("set encoding utf8\n".as_bytes())?;
-        stdin.write_all(gnuplot_input.as_bytes())?;
+        let input = format!(
+            "set output '{}'\nset terminal svg\nset encoding utf8\n{}",
+            Path::new(name).display(),
+            gnuplot_input
+        );
+        runner.run_checked(
+            Command::new(gnuplot_path).current_dir(fragment_path),
+            Some(input.as_bytes()),
+        )?;
+    }
+
+    Ok(svg_fragment_path.to_owned())
+}
+
+/// Run a matplotlib script with `python3` and save the current figure
+///
+/// The backend follows the extension of `output`, `svg` or `pgf`. Lines of tracebacks are
+/// reported relative to the chapter.
+pub fn parse_python(
+    fragment_path: &Path,
+    output: &Path,
+    python_input: &str,
+    origin: &Origin,
+    runner: &Runner,
+) -> Result<PathBuf> {
+    if output.exists() {
+        return Ok(output.to_owned());
     }
+    let name = output
+        .file_name()
+        .expect("Fragment file always has a name. qed");
+    let backend = output
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("svg");
+
+    // the script is the plain source, its line numbers only shifted by the opening delimiter
+    let input = format!(
+        "{}\nimport matplotlib.pyplot as plt\nplt.gcf().savefig({:?}, bbox_inches='tight')\n",
+        python_input,
+        Path::new(name).display().to_string(),
+    );
+    let python_path = find_program("python3")?;
+    let mut command = Command::new(python_path);
+    command
+        .current_dir(fragment_path)
+        .env("MPLBACKEND", backend)
+        .arg("-");
+    runner
+        .run_checked(&mut command, Some(input.as_bytes()))
+        .map_err(|err| match err {
+            ScientificError::ToolFailed {
+                cmd,
+                status,
+                output,
+            } => ScientificError::ToolFailed {
+                cmd,
+                status,
+                output: relocate_traceback(&output, origin),
+            },
+            err => err,
+        })?;
+
+    Ok(output.to_owned())
+}
+
+/// Point the `<stdin>` lines of a python traceback to the chapter source.
+fn relocate_traceback(traceback: &str, origin: &Origin) -> String {
+    let line = Regex::new(r#"File "<stdin>", line (\d+)"#).expect("Regex is valid. qed");
+    line.replace_all(traceback, |captures: &regex::Captures| {
+        let lineno = captures[1].parse::<usize>().unwrap_or(1);
+        format!(
+            r#"File "{}", line {}"#,
+            origin.chapter_path.display(),
+            origin.lineno + lineno.saturating_sub(1)
+        )
+    })
+    .into_owned()
+}
 
+/// Convert an equation to MathML, without any external tools
+pub fn generate_mathml<'a>(content: &Content<'a>, block: bool) -> Result<Replacement<'a>> {
     Ok(Replacement {
         content: content.clone(),
         intermediate: None,
-        svg_fragment_file: svg_fragment_path,
-        svg_asset_file: svg_asset_path,
+        rendered: Rendered::MathMl(equation_to_mathml(content, block)?),
+        fallback: None,
+    })
+}
+
+/// The MathML markup of an equation
+pub fn equation_to_mathml(content: &Content<'_>, block: bool) -> Result<String> {
+    let display = if block {
+        DisplayStyle::Block
+    } else {
+        DisplayStyle::Inline
+    };
+    latex_to_mathml(content.trimmed().as_str(), display).map_err(|err| {
+        ScientificError::InvalidMathMl {
+            msg: err.to_string(),
+            lineno: content.start.lineno,
+        }
     })
 }
 
+/// Handle an equation according to an in-process math mode
+pub fn generate_in_process<'a>(
+    content: &Content<'a>,
+    math: MathMode,
+    block: bool,
+) -> Result<Replacement<'a>> {
+    match math {
+        MathMode::MathMl => generate_mathml(content, block),
+        MathMode::Passthrough => Ok(Replacement {
+            content: content.clone(),
+            intermediate: None,
+            rendered: Rendered::Passthrough,
+            fallback: None,
+        }),
+        MathMode::Svg => unreachable!("Svg requires external tools, checked by caller. qed"),
+    }
+}
+
 /// Generate html from BibTeX file using `bib2xhtml`
-pub fn bib_to_html(source: &str, bib2xhtml: &str) -> Result<String> {
+pub fn bib_to_html(source: &str, bib2xhtml: &str, runner: &Runner) -> Result<String> {
     let source = fs::canonicalize(source)?;
     let bib2xhtml = Path::new(bib2xhtml);
 
     //./bib2xhtml.pl -s alpha -u -U ~/Documents/Bachelor_thesis/literature.bib
-    let cmd = Command::new(bib2xhtml.join("./bib2xhtml.pl"))
-        .current_dir(bib2xhtml)
-        .args(["-s", "alpha", "-u", "-U"])
-        .arg(source)
-        .output()
-        .expect("Could not spawn bib2xhtml");
+    let cmd = runner.run(
+        Command::new(bib2xhtml.join("./bib2xhtml.pl"))
+            .current_dir(bib2xhtml)
+            .args(["-s", "alpha", "-u", "-U"])
+            .arg(source),
+        None,
+    )?;
 
     let buf = String::from_utf8_lossy(&cmd.stdout);
 
@@ -339,3 +800,69 @@ pub fn bib_to_html(source: &str, bib2xhtml: &str) -> Result<String> {
         Ok(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdf_engines_convert_glyphs_to_paths_as_well() {
+        let inline = SvgOptions {
+            zoom: 1.5,
+            fonts: false,
+        };
+        assert_eq!(
+            dvisvgm_args(true, inline),
+            ["-b", "1", "--pdf", "--no-fonts", "--zoom=1.5"]
+        );
+        assert_eq!(
+            dvisvgm_args(
+                false,
+                SvgOptions {
+                    fonts: true,
+                    ..inline
+                }
+            ),
+            ["-b", "1", "--font-format=woff", "--zoom=1.5"]
+        );
+    }
+
+    #[test]
+    fn latex_errors_point_into_the_chapter() {
+        let origin = Origin {
+            chapter_path: PathBuf::from("src/ch2.md"),
+            lineno: 40,
+            column: 1,
+            lines: vec![
+                "$$equation, euler".to_owned(),
+                "e^{i \\pi} = \\foo{1}".to_owned(),
+                "$$".to_owned(),
+            ],
+        };
+        let map = SourceMap {
+            origin: &origin,
+            first_line: 12,
+            prefix: "$$",
+        };
+        let err = map.locate("Undefined control sequence.", "e^{i \\pi} = \\foo", 13);
+        assert_eq!(
+            err.to_string(),
+            "src/ch2.md:41:13: Undefined control sequence `\\foo`\n   |\n41 | e^{i \\pi} = \\foo{1}\n   |             ^"
+        );
+    }
+
+    #[test]
+    fn python_tracebacks_point_into_the_chapter() {
+        let origin = Origin {
+            chapter_path: PathBuf::from("src/plots.md"),
+            lineno: 12,
+            column: 1,
+            lines: Vec::new(),
+        };
+        let traceback = "Traceback (most recent call last):\n  File \"<stdin>\", line 3, in <module>\nNameError: name 'np' is not defined";
+        assert_eq!(
+            relocate_traceback(traceback, &origin),
+            "Traceback (most recent call last):\n  File \"src/plots.md\", line 14, in <module>\nNameError: name 'np' is not defined"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! The words the preprocessor adds to the book, in the language of the book.
+
+use mdbook_boilerplate::Table;
+
+use crate::config;
+use crate::errors::{Result, ScientificError};
+
+/// Caption prefixes, reference prefixes and headings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Labels {
+    /// Prefix of figure captions and references, `Figure 1.2`
+    pub figure: String,
+    /// Prefix of equation references, `Eq. (1.2)`
+    pub equation: String,
+    /// Title of the bibliography chapter
+    pub bibliography: String,
+    /// Heading of the cited entries at the end of a chapter
+    pub references: String,
+    pub list_of_figures: String,
+    pub list_of_equations: String,
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Self::new(
+            ["Figure", "Eq.", "Bibliography", "References"],
+            ["List of Figures", "List of Equations"],
+        )
+    }
+}
+
+impl Labels {
+    fn new(
+        [figure, equation, bibliography, references]: [&str; 4],
+        [list_of_figures, list_of_equations]: [&str; 2],
+    ) -> Self {
+        Self {
+            figure: figure.to_owned(),
+            equation: equation.to_owned(),
+            bibliography: bibliography.to_owned(),
+            references: references.to_owned(),
+            list_of_figures: list_of_figures.to_owned(),
+            list_of_equations: list_of_equations.to_owned(),
+        }
+    }
+
+    /// The built-in labels for a language code such as `de` or `fr-CA`, English if unknown.
+    pub fn for_language(language: &str) -> Self {
+        let primary = language
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match primary.as_str() {
+            "en" => Self::default(),
+            "de" => Self::new(
+                ["Abbildung", "Gl.", "Literaturverzeichnis", "Literatur"],
+                ["Abbildungsverzeichnis", "Formelverzeichnis"],
+            ),
+            "fr" => Self::new(
+                ["Figure", "Éq.", "Bibliographie", "Références"],
+                ["Table des figures", "Liste des équations"],
+            ),
+            "es" => Self::new(
+                ["Figura", "Ec.", "Bibliografía", "Referencias"],
+                ["Índice de figuras", "Índice de ecuaciones"],
+            ),
+            "it" => Self::new(
+                ["Figura", "Eq.", "Bibliografia", "Riferimenti"],
+                ["Elenco delle figure", "Elenco delle equazioni"],
+            ),
+            "pt" => Self::new(
+                ["Figura", "Eq.", "Bibliografia", "Referências"],
+                ["Lista de figuras", "Lista de equações"],
+            ),
+            "nl" => Self::new(
+                ["Figuur", "Vgl.", "Bibliografie", "Referenties"],
+                ["Lijst van figuren", "Lijst van vergelijkingen"],
+            ),
+            _ => {
+                log::warn!("No labels for language `{}`, using English", language);
+                Self::default()
+            }
+        }
+    }
+
+    /// The labels for the book `language`, with those given in the `labels` table replaced.
+    pub fn from_cfg(cfg: &Table, language: Option<&str>) -> Result<Self> {
+        let mut labels = language.map(Self::for_language).unwrap_or_default();
+        let overrides = match config::get_table(cfg, "labels")? {
+            Some(overrides) => overrides,
+            None => return Ok(labels),
+        };
+        for key in overrides.keys() {
+            let label = match key.as_str() {
+                "figure" => &mut labels.figure,
+                "equation" => &mut labels.equation,
+                "bibliography" => &mut labels.bibliography,
+                "references" => &mut labels.references,
+                "list_of_figures" => &mut labels.list_of_figures,
+                "list_of_equations" => &mut labels.list_of_equations,
+                _ => {
+                    return Err(ScientificError::InvalidConfigValue {
+                        key: "labels".to_owned(),
+                        value: key.to_owned(),
+                    })
+                }
+            };
+            if let Some(value) = config::get_str(overrides, key)? {
+                *label = value.to_owned();
+            }
+        }
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_with_overrides() {
+        let cfg: Table = serde_json::from_value(serde_json::json!({
+            "labels": { "equation": "Gleichung" },
+        }))
+        .unwrap();
+        let labels = Labels::from_cfg(&cfg, Some("de-AT")).unwrap();
+        assert_eq!(labels.figure, "Abbildung");
+        assert_eq!(labels.equation, "Gleichung");
+        assert_eq!(labels.bibliography, "Literaturverzeichnis");
+
+        assert_eq!(
+            Labels::from_cfg(&Table::new(), None).unwrap(),
+            Labels::default()
+        );
+    }
+}
@@ -1,11 +1,22 @@
+mod bibliography;
+mod cache;
+mod config;
+mod data;
+mod engine;
 mod fragments;
+mod labels;
+pub mod lint;
 mod preprocess;
+mod runner;
+mod svg;
+mod template;
 
 pub use mathyank::*;
 
 use crate::errors::ScientificError;
 use fs_err as fs;
-use mdbook_boilerplate::{asset_path, fragment_path};
+use mdbook_boilerplate::{asset_path, fragment_path, Table};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -14,7 +25,13 @@ use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use nom_bibtex::*;
 
-use crate::preprocess::replace_blocks;
+use crate::bibliography::{Bibliography, BibliographyConfig};
+use crate::cache::{CachedChapter, ChapterCache, ChapterIndex, Manifest, ToolVersions};
+use crate::engine::TexEngine;
+use crate::labels::Labels;
+use crate::preprocess::{collect_fragments, format_list, replace_blocks, ChapterInfo, Settings};
+use crate::runner::Runner;
+use crate::template::FragmentTemplate;
 
 pub mod errors;
 pub use self::errors::*;
@@ -52,6 +69,113 @@ impl Preprocessor for Scientific {
     }
 }
 
+/// The section number as used as prefix for figures and equations
+fn chapter_number(ch: &Chapter) -> String {
+    ch.number
+        .as_ref()
+        .map(|x| x.to_string())
+        .unwrap_or_default()
+}
+
+/// The chapter source file relative to the book root, i.e. `src/ch2.md`
+fn source_path(ctx: &PreprocessorContext, ch: &Chapter) -> PathBuf {
+    ch.source_path
+        .as_ref()
+        .or(ch.path.as_ref())
+        .map(|path| ctx.config.book.src.join(path))
+        .unwrap_or_default()
+}
+
+/// Whether `to` was copied from `from` after its last modification.
+fn is_copy(from: &Path, to: &Path) -> bool {
+    let metadata = |path: &Path| {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.len(), metadata.modified().ok()?))
+    };
+    match (metadata(from), metadata(to)) {
+        (Some((from_len, modified)), Some((to_len, copied))) => {
+            from_len == to_len && copied >= modified
+        }
+        _ => false,
+    }
+}
+
+/// Add the enabled lists of figures and equations, before `lists_before` or at the end.
+fn add_lists(
+    cfg: &Table,
+    book: &mut Book,
+    references: &ReferenceTracker,
+    labels: &Labels,
+) -> Result<()> {
+    let lists = [
+        (
+            "list_of_figures",
+            RefKind::Figure,
+            &labels.list_of_figures,
+            format!("{} {{}}", labels.figure),
+        ),
+        (
+            "list_of_equations",
+            RefKind::Equation,
+            &labels.list_of_equations,
+            format!("{} ({{}})", labels.equation),
+        ),
+    ];
+    let before = config::get_str(cfg, "lists_before")?.map(Path::new);
+    for (key, kind, heading, label) in lists {
+        if !config::get_bool(cfg, key)?.unwrap_or(false) {
+            continue;
+        }
+        let listed = Vec::from_iter(references.listed(kind));
+        let chapter = Chapter::new(
+            heading,
+            format_list(&listed, heading, &label),
+            PathBuf::from(format!("{}.md", key)),
+            Vec::new(),
+        );
+        match before {
+            Some(before) => {
+                if insert_before(&mut book.sections, before, chapter).is_some() {
+                    return Err(ScientificError::InvalidConfigValue {
+                        key: "lists_before".to_owned(),
+                        value: before.display().to_string(),
+                    });
+                }
+            }
+            None => {
+                book.push_item(chapter);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Insert `chapter` as sibling before the chapter with the source file `before`.
+///
+/// Hands the chapter back if there is none.
+fn insert_before(
+    items: &mut Vec<BookItem>,
+    before: &Path,
+    mut chapter: Chapter,
+) -> Option<Chapter> {
+    let position = items.iter().position(
+        |item| matches!(item, BookItem::Chapter(ch) if ch.source_path.as_deref() == Some(before)),
+    );
+    if let Some(position) = position {
+        if let BookItem::Chapter(ref sibling) = items[position] {
+            chapter.parent_names = sibling.parent_names.clone();
+        }
+        items.insert(position, BookItem::Chapter(chapter));
+        return None;
+    }
+    for item in items.iter_mut() {
+        if let BookItem::Chapter(ref mut ch) = item {
+            chapter = insert_before(&mut ch.sub_items, before, chapter)?;
+        }
+    }
+    Some(chapter)
+}
+
 impl Scientific {
     fn run_inner(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
         if let Some(cfg) = ctx.config.get_preprocessor(self.name()) {
@@ -73,11 +197,50 @@ impl Scientific {
             let mut used_fragments = Vec::new();
             // track which references are created
             let mut references = ReferenceTracker::new();
-            // if there occurs an error skip everything and return the error
-            let mut error = Ok::<_, ScientificError>(());
+            // failed items of all chapters, reported together
+            let mut errors = Vec::new();
+            let continue_on_error = config::get_bool(cfg, "continue_on_error")?.unwrap_or(false);
+
+            let epub = renderer == SupportedRenderer::Epub;
+            let engine = config::get_parsed::<TexEngine>(cfg, "engine")?.unwrap_or_default();
+            log::info!("Using TeX engine: {}", engine.as_str());
+            let template = FragmentTemplate::from_cfg(cfg, &ctx.root, engine)?;
+            let labels = Labels::from_cfg(cfg, ctx.config.book.language.as_deref())?;
+            let runner = Runner::from_cfg(cfg)?;
+            let bib_cfg = BibliographyConfig::from_cfg(cfg)?;
+
+            // images are referenced relative to the source directory, the only one packaged
+            let asset_link = match asset_path.strip_prefix(&ctx.config.book.src) {
+                Ok(asset_link) if epub => asset_link.to_owned(),
+                Err(_) if epub => {
+                    log::warn!(
+                        "Asset path {} is outside of the source directory, add it to `additional-resources` of `[output.epub]`",
+                        asset_path.display()
+                    );
+                    asset_path.clone()
+                }
+                _ => asset_path.clone(),
+            };
+
+            let root = fs::canonicalize(&ctx.root)?;
+            let settings = Settings::from_cfg(
+                cfg,
+                renderer,
+                &root,
+                &fragment_path,
+                &asset_link,
+                &template,
+                &labels,
+            )?;
+            let pandoc = settings.is_pandoc();
+
+            // selected and numbered entries plus the citations, if a bibliography is configured
+            let mut bibliography = None;
 
             match renderer {
-                SupportedRenderer::Markdown | SupportedRenderer::Html => {
+                SupportedRenderer::Markdown | SupportedRenderer::Html | SupportedRenderer::Epub
+                    if !pandoc =>
+                {
                     // load all references in the bibliography and export to html
                     if let (Some(bib), Some(bib2xhtml)) =
                         (cfg.get("bibliography"), cfg.get("bib2xhtml"))
@@ -92,80 +255,208 @@ impl Scientific {
                         // read entries in bibtex file
                         let bibtex = fs::read_to_string(bib)?;
                         let bibtex = Bibtex::parse(&bibtex)?;
-                        for (i, entry) in bibtex.bibliographies().iter().enumerate() {
-                            references
-                                .add(entry.citation_key().to_string(), format!("[{}]", i + 1));
-                        }
+                        let keys = Vec::from_iter(
+                            bibtex
+                                .bibliographies()
+                                .iter()
+                                .map(|entry| entry.citation_key().to_string()),
+                        );
 
-                        // create bibliography
-                        let content = fragments::bib_to_html(bib, bib2xhtml)?;
+                        // number entries by the citations found in all chapters
+                        let citations = bibliography::collect_citations(&book);
+                        let content = fragments::bib_to_html(bib, bib2xhtml, &runner)?;
+                        let selected = Bibliography::new(&keys, &content, &bib_cfg, &citations);
+                        for (key, label) in selected.labels() {
+                            references.add(key, label);
+                        }
 
-                        // add final chapter for bibliography
-                        let bib_chapter = Chapter::new(
-                            "Bibliography",
-                            format!("# Bibliography\n{}", content),
-                            PathBuf::from("bibliography.md"),
-                            Vec::new(),
-                        );
-                        book.push_item(bib_chapter);
+                        if bib_cfg.placement.has_book_chapter() {
+                            // add final chapter for bibliography
+                            let bib_chapter = Chapter::new(
+                                &labels.bibliography,
+                                format!("# {}\n{}", labels.bibliography, selected.render()),
+                                PathBuf::from("bibliography.md"),
+                                Vec::new(),
+                            );
+                            book.push_item(bib_chapter);
+                        }
+                        bibliography = Some((selected, citations));
                     }
                 }
-                SupportedRenderer::Tectonic | SupportedRenderer::Latex => {
+                _ => {
                     //native support for bibtex, no need to fuck around
+                    // the same goes for pandoc with `--citeproc`
                 }
             }
 
-            // process blocks like `$$ .. $$`
-            book.for_each_mut(|item| {
-                if let Err(_) = error {
-                    log::debug!("Previous error, skipping chapter processing.");
-                    return;
+            // collect all labels and unique fragments across all chapters
+            let mut fragments = BTreeMap::new();
+            let mut index = ChapterIndex::new();
+            let mut chapter_uses = HashMap::new();
+            for item in book.iter() {
+                if let BookItem::Chapter(ref ch) = item {
+                    let chapter_number = chapter_number(ch);
+                    let source_path = source_path(ctx, ch);
+                    let link = ch.path.clone().unwrap_or_default();
+                    let chapter = ChapterInfo {
+                        number: &chapter_number,
+                        path: &source_path,
+                        link: &link,
+                    };
+                    let uses = collect_fragments(
+                        &ch.content,
+                        &settings,
+                        &chapter,
+                        &mut fragments,
+                        &mut references,
+                        &mut errors,
+                    );
+                    let key = source_path.display().to_string();
+                    index.insert(key.clone(), uses.fragments.clone());
+                    chapter_uses.insert(key, uses);
                 }
+            }
+
+            let referenced = HashSet::from_iter(
+                chapter_uses
+                    .values()
+                    .flat_map(|uses| uses.references.iter().map(String::as_str)),
+            );
+            for (label, origin) in references.unused(&referenced) {
+                log::warn!("{}: Label `{}` is never referenced", origin, label);
+            }
+
+            // render those that are not up to date in parallel
+            let jobs = config::get_integer(cfg, "jobs")?
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+            let fragments = Vec::from_iter(fragments.into_values());
+            let mut manifest = Manifest::load(&fragment_path);
+            let mut tools = ToolVersions::new(runner);
+            let stale = manifest.stale(&fragments, &mut tools)?;
+            let failed = fragments::render_all(&stale, jobs, &runner);
+            manifest.record(stale, &mut tools)?;
+            manifest.update_index(ctx.renderer.as_str(), index);
+            if config::get_bool(cfg, "fragment_cleanup")?.unwrap_or(true) {
+                manifest.collect_garbage(&asset_path)?;
+            }
+            manifest.save()?;
+            let failed_fragments = HashSet::from_iter(
+                failed
+                    .iter()
+                    .map(|(fragment, _)| fragment.fragment_file.clone()),
+            );
+            errors.extend(failed.into_iter().map(|(fragment, source)| ItemError {
+                origin: fragment.origin.clone(),
+                source,
+            }));
 
+            // process blocks like `$$ .. $$`, unless the chapter and all it uses are unchanged
+            let fragment_inputs = HashMap::from_iter(
+                fragments
+                    .iter()
+                    .map(|fragment| (fragment.store_name(), manifest.provenance(fragment))),
+            );
+            let mut chapter_cache = ChapterCache::load(&fragment_path, ctx.renderer.as_str());
+            book.for_each_mut(|item| {
                 if let BookItem::Chapter(ref mut ch) = item {
-                    let chapter_number = ch
-                        .number
-                        .as_ref()
-                        .map(|x| x.to_string())
-                        .unwrap_or_default();
+                    let chapter_number = chapter_number(ch);
                     let chapter_path = ch.path.as_ref().cloned().unwrap_or_else(|| PathBuf::new());
-                    let chapter_name = ch.name.clone();
+                    let source_path = source_path(ctx, ch);
+                    let chapter = ChapterInfo {
+                        number: &chapter_number,
+                        path: &source_path,
+                        link: &chapter_path,
+                    };
 
-                    match replace_blocks(
-                        &fragment_path,
-                        &asset_path,
-                        &ch.content,
-                        &chapter_number,
-                        &chapter_name,
-                        &chapter_path,
-                        renderer,
-                        &mut used_fragments,
-                        &mut references,
-                    ) {
-                        Ok(mut reconstructed) => {
-                            reconstructed.push('\n');
-                            if reconstructed != ch.content {
-                                // for line in ch.content.lines() {
-                                //     eprintln!("- {}", line);
-                                // }
-                                // for line in reconstructed.lines() {
-                                //     eprintln!("+ {}", line);
-                                // }
-                                reconstructed.push('\n');
-                                ch.content = reconstructed;
+                    let cache_key = source_path.display().to_string();
+                    let key = chapter_uses.get(&cache_key).map(|uses| {
+                        uses.key(
+                            &ch.content,
+                            &settings,
+                            &chapter,
+                            &references,
+                            &fragment_inputs,
+                            &failed_fragments,
+                        )
+                    });
+                    let cached = key
+                        .as_deref()
+                        .and_then(|key| chapter_cache.get(&cache_key, key))
+                        .cloned();
+                    let mut reconstructed = match cached {
+                        Some(cached) => {
+                            log::debug!("Chapter {} is up to date", cache_key);
+                            used_fragments.extend(cached.fragments.iter().cloned());
+                            let content = cached.content.clone();
+                            chapter_cache.keep(&cache_key, cached);
+                            content
+                        }
+                        None => {
+                            let mut fragments = Vec::new();
+                            let errors_before = errors.len();
+                            let content = replace_blocks(
+                                &ch.content,
+                                &settings,
+                                &chapter,
+                                &mut fragments,
+                                &references,
+                                &failed_fragments,
+                                &mut errors,
+                            );
+                            // failed items must be reported again
+                            if let Some(key) = key.filter(|_| errors.len() == errors_before) {
+                                chapter_cache.keep(
+                                    &cache_key,
+                                    CachedChapter {
+                                        key,
+                                        content: content.clone(),
+                                        fragments: fragments.clone(),
+                                    },
+                                );
                             }
+                            used_fragments.extend(fragments);
+                            content
                         }
-                        Err(err) => error = Err(err),
+                    };
+                    reconstructed.push('\n');
+                    // list the entries cited within this chapter
+                    if let Some((ref selected, ref citations)) = bibliography {
+                        if bib_cfg.placement.has_chapter_sections() {
+                            if let Some(keys) = citations.per_chapter.get(&chapter_path) {
+                                reconstructed.push_str(&format!(
+                                    "\n## {}\n{}\n",
+                                    labels.references,
+                                    selected.render_subset(keys)
+                                ));
+                            }
+                        }
+                    }
+                    if reconstructed != ch.content {
+                        reconstructed.push('\n');
+                        ch.content = reconstructed;
                     }
                 }
             });
 
-            error?;
+            chapter_cache.save()?;
 
-            // copy all used fragments
+            if !errors.is_empty() {
+                errors.sort_by(|a, b| a.origin.cmp(&b.origin));
+                let err = ScientificError::ItemsFailed(errors);
+                if !continue_on_error {
+                    return Err(err);
+                }
+                log::error!("{}, left placeholders in their place", err);
+            }
+
+            if renderer.is_html_like() {
+                add_lists(cfg, &mut book, &references, &labels)?;
+            }
+
+            // copy all used fragments, unless copied before
             if fragment_path != asset_path {
                 // svg_path is unfortunately the `fragment_path` plus `file` which is an abs path.
-                for fragment in used_fragments {
+                for fragment in BTreeSet::from_iter(used_fragments) {
                     let from = fragment;
                     let fragment = from
                         .strip_prefix(&fragment_path)
@@ -173,6 +464,10 @@ impl Scientific {
                         .to_owned();
                     // let from = fragment_path.join(&fragment);
                     let to = asset_path.join(&fragment);
+                    if is_copy(&from, &to) {
+                        log::debug!("Fragment {} is in the assets dir already", to.display());
+                        continue;
+                    }
                     log::info!(
                         "Copying fragment to assets dir: {} -> {}",
                         from.display(),
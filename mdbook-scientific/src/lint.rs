@@ -0,0 +1,359 @@
+//! Check a book for broken math, figures and references without building it.
+//!
+//! Runs the collect and substitution phases of the preprocessor on every chapter, the
+//! equations are only compiled on request. Diagnostics are printed for humans, as JSON or as
+//! GitHub workflow annotations.
+
+use fs_err as fs;
+use mdbook::book::BookItem;
+use mdbook::MDBook;
+use mdbook_boilerplate::{fragment_path, Table};
+use nom_bibtex::Bibtex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::cache::{Manifest, ToolVersions};
+use crate::config;
+use crate::engine::TexEngine;
+use crate::errors::{ItemError, Result, ScientificError};
+use crate::fragments;
+use crate::labels::Labels;
+use crate::preprocess::{collect_fragments, replace_blocks, ChapterInfo, Settings};
+use crate::runner::Runner;
+use crate::template::FragmentTemplate;
+use crate::types::*;
+
+/// How diagnostics are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LintFormat {
+    /// `src/ch2.md:41:13: error: ..`, one per line
+    #[default]
+    Human,
+    /// A JSON array of diagnostics
+    Json,
+    /// Workflow commands GitHub shows as annotations of the changed files
+    Github,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A single finding, located in the book source
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Chapter source file, relative to the current directory
+    pub file: PathBuf,
+    /// Base 1
+    pub line: usize,
+    /// Base 1
+    pub column: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl From<&ItemError> for Diagnostic {
+    fn from(item: &ItemError) -> Self {
+        let (file, line, column, message) = match item.source {
+            // more precise than the origin of the item
+            ScientificError::InvalidMathAt {
+                ref chapter_path,
+                lineno,
+                column,
+                ref msg,
+                ..
+            } => (chapter_path, lineno, column, msg.clone()),
+            ref source => (
+                &item.origin.chapter_path,
+                item.origin.lineno,
+                item.origin.column,
+                source.to_string(),
+            ),
+        };
+        Self {
+            severity: Severity::Error,
+            file: file.clone(),
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+/// Escape data of a GitHub workflow command, `properties` additionally escapes `:` and `,`
+fn escape_github(s: &str, property: bool) -> String {
+    let s = s
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    if property {
+        s.replace(':', "%3A").replace(',', "%2C")
+    } else {
+        s
+    }
+}
+
+/// Print `diagnostics` in the given format, including the trailing newline
+pub fn format_diagnostics(diagnostics: &[Diagnostic], format: LintFormat) -> Result<String> {
+    let lines = match format {
+        LintFormat::Json => vec![serde_json::to_string_pretty(diagnostics)?],
+        LintFormat::Human => Vec::from_iter(diagnostics.iter().map(|diagnostic| {
+            format!(
+                "{}:{}:{}: {}: {}",
+                diagnostic.file.display(),
+                diagnostic.line,
+                diagnostic.column,
+                diagnostic.severity,
+                diagnostic.message
+            )
+        })),
+        LintFormat::Github => Vec::from_iter(diagnostics.iter().map(|diagnostic| {
+            format!(
+                "::{} file={},line={},col={}::{}",
+                diagnostic.severity,
+                escape_github(&diagnostic.file.display().to_string(), true),
+                diagnostic.line,
+                diagnostic.column,
+                escape_github(&diagnostic.message, false)
+            )
+        })),
+    };
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+/// Check all chapters of the book in `book_dir`, ordered by their location.
+///
+/// With `compile`, equations are rendered into the fragment directory of the book with the
+/// options of the book, so a following html build reuses them. Figures are never rendered.
+pub fn lint(book_dir: &Path, compile: bool) -> Result<Vec<Diagnostic>> {
+    let md = MDBook::load(book_dir)?;
+    let empty = Table::new();
+    let cfg = md.config.get_preprocessor("scientific").unwrap_or(&empty);
+
+    // only created when compiling, the check alone writes nothing
+    let mut fragment_path = md.root.join(fragment_path(cfg));
+    if compile {
+        fs::create_dir_all(&fragment_path)?;
+        fragment_path = fs::canonicalize(fragment_path)?;
+    }
+    let asset_path = md.root.join(mdbook_boilerplate::asset_path(cfg));
+
+    let engine = config::get_parsed::<TexEngine>(cfg, "engine")?.unwrap_or_default();
+    let template = FragmentTemplate::from_cfg(cfg, &md.root, engine)?;
+    let labels = Labels::from_cfg(cfg, md.config.book.language.as_deref())?;
+    let runner = Runner::from_cfg(cfg)?;
+
+    let mut references = ReferenceTracker::new();
+    if let Some(bib) = cfg.get("bibliography").and_then(|bib| bib.as_str()) {
+        let path = md.root.join(bib);
+        if !path.exists() {
+            return Err(ScientificError::BibliographyMissing(bib.to_owned()));
+        }
+        let bibtex = fs::read_to_string(path)?;
+        for entry in Bibtex::parse(&bibtex)?.bibliographies() {
+            references.add(entry.citation_key(), entry.citation_key());
+        }
+    }
+
+    // the fragments of an html build, so it reuses those compiled here
+    let root = fs::canonicalize(&md.root)?;
+    let mut settings = Settings::from_cfg(
+        cfg,
+        SupportedRenderer::Html,
+        &root,
+        &fragment_path,
+        &asset_path,
+        &template,
+        &labels,
+    )?;
+    if !compile {
+        // equations stay text instead of fragments that are never rendered
+        settings.math = MathMode::Passthrough;
+    }
+
+    let chapters = Vec::from_iter(md.book.iter().filter_map(|item| match item {
+        BookItem::Chapter(ch) => {
+            let number = ch
+                .number
+                .as_ref()
+                .map(|x| x.to_string())
+                .unwrap_or_default();
+            let path = ch
+                .source_path
+                .as_ref()
+                .or(ch.path.as_ref())
+                .map(|path| md.root.join(&md.config.book.src).join(path))
+                .unwrap_or_default();
+            let link = ch.path.clone().unwrap_or_default();
+            Some((ch, number, path, link))
+        }
+        _ => None,
+    }));
+
+    let mut errors = Vec::new();
+    let mut fragments = BTreeMap::new();
+    let mut referenced = HashSet::new();
+    for (ch, number, path, link) in &chapters {
+        let chapter = ChapterInfo { number, path, link };
+        let uses = collect_fragments(
+            &ch.content,
+            &settings,
+            &chapter,
+            &mut fragments,
+            &mut references,
+            &mut errors,
+        );
+        referenced.extend(uses.references);
+    }
+
+    // unrendered fragments are skipped when substituting, their errors were reported already
+    let mut failed_fragments = HashSet::from_iter(fragments.keys().cloned());
+    if compile {
+        let equations = Vec::from_iter(
+            fragments
+                .into_values()
+                .filter(|fragment| fragment.kind == mathyank::EquBlockKind::Equation),
+        );
+        let jobs = config::get_integer(cfg, "jobs")?
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+        let mut manifest = Manifest::load(&fragment_path);
+        let mut tools = ToolVersions::new(runner);
+        let stale = manifest.stale(&equations, &mut tools)?;
+        let failed = fragments::render_all(&stale, jobs, &runner);
+        manifest.record(stale, &mut tools)?;
+        manifest.save()?;
+        for equation in &equations {
+            failed_fragments.remove(&equation.fragment_file);
+        }
+        for (fragment, source) in failed {
+            failed_fragments.insert(fragment.fragment_file.clone());
+            errors.push(ItemError {
+                origin: fragment.origin.clone(),
+                source,
+            });
+        }
+    }
+
+    for (ch, number, path, link) in &chapters {
+        let chapter = ChapterInfo { number, path, link };
+        replace_blocks(
+            &ch.content,
+            &settings,
+            &chapter,
+            &mut Vec::new(),
+            &references,
+            &failed_fragments,
+            &mut errors,
+        );
+    }
+
+    let referenced = HashSet::from_iter(referenced.iter().map(String::as_str));
+    let unused = references.unused(&referenced);
+    let mut diagnostics = Vec::from_iter(errors.iter().map(Diagnostic::from).chain(
+        unused.into_iter().map(|(label, origin)| Diagnostic {
+            severity: Severity::Warning,
+            file: origin.chapter_path.clone(),
+            line: origin.lineno,
+            column: origin.column,
+            message: format!("Label `{}` is never referenced", label),
+        }),
+    ));
+    diagnostics.sort_by(|a, b| {
+        (&a.file, a.line, a.column, b.severity).cmp(&(&b.file, b.line, b.column, a.severity))
+    });
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+
+    #[test]
+    fn lint_reports_broken_items_of_all_chapters() {
+        let dir = TempDir::new("lint");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("book.toml"), "[book]\ntitle = \"Lint\"\n").unwrap();
+        fs::write(
+            dir.join("src/SUMMARY.md"),
+            "# Summary\n\n- [One](one.md)\n- [Two](two.md)\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("src/one.md"),
+            "# One\n\n$$equation, euler\ne^{i\\pi} = -1\n$$\n\n$$equation, lonely\nx = 1\n$$\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("src/two.md"),
+            "# Two\n\nSee $ref:equ:eulr$.\n\n$$equation, euler\ne = 2.7\n$$\n\nAlso $ref:equ:euler$.\n",
+        )
+        .unwrap();
+
+        let diagnostics = lint(&dir, false).unwrap();
+        let found = Vec::from_iter(diagnostics.iter().map(|diagnostic| {
+            (
+                diagnostic.file.strip_prefix(&dir).unwrap().to_owned(),
+                diagnostic.severity,
+                diagnostic.message.clone(),
+            )
+        }));
+        assert_eq!(found.len(), 3, "{:#?}", found);
+        assert_eq!(
+            found[0],
+            (
+                PathBuf::from("src/one.md"),
+                Severity::Warning,
+                "Label `lonely` is never referenced".to_owned()
+            )
+        );
+        assert_eq!(found[1].0, PathBuf::from("src/two.md"));
+        assert!(found[1].2.ends_with(", did you mean `euler`?"));
+        assert_eq!(found[2].0, PathBuf::from("src/two.md"));
+        assert!(found[2]
+            .2
+            .starts_with("Label `euler` is defined already at "));
+        assert!(diagnostics.iter().any(Diagnostic::is_error));
+        // a check without `--compile` leaves the book as it is
+        assert!(!dir.join("fragments").exists());
+    }
+
+    #[test]
+    fn github_annotations_are_escaped() {
+        let diagnostics = [Diagnostic {
+            severity: Severity::Error,
+            file: PathBuf::from("docs/src/a,b.md"),
+            line: 3,
+            column: 5,
+            message: "Undefined control sequence\n100% wrong".to_owned(),
+        }];
+        assert_eq!(
+            format_diagnostics(&diagnostics, LintFormat::Github).unwrap(),
+            "::error file=docs/src/a%2Cb.md,line=3,col=5::Undefined control sequence%0A100%25 wrong\n"
+        );
+        assert_eq!(
+            format_diagnostics(&diagnostics, LintFormat::Human).unwrap(),
+            "docs/src/a,b.md:3:5: error: Undefined control sequence\n100% wrong\n"
+        );
+    }
+}
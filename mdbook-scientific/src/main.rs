@@ -1,6 +1,41 @@
 use mdbook_boilerplate::*;
+use mdbook_scientific::lint::{self, LintFormat};
+use std::path::PathBuf;
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Check the math, figures and references of a book without building it
+    Lint {
+        /// Directory containing the `book.toml`
+        #[arg(default_value = ".")]
+        book_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: LintFormat,
+        /// Compile every equation, rather than only parsing it
+        #[arg(long)]
+        compile: bool,
+    },
+}
 
 fn main() -> Result<()> {
-    let args = Args::try_parse()?;
-    launch(mdbook_scientific::Scientific::new(), args, "🧫")
+    let args = ArgsWith::<Command>::try_parse()?;
+    launch_with(
+        mdbook_scientific::Scientific::new(),
+        args,
+        "🧫",
+        |command| match command {
+            Command::Lint {
+                book_dir,
+                format,
+                compile,
+            } => {
+                let diagnostics = lint::lint(&book_dir, compile)?;
+                print!("{}", lint::format_diagnostics(&diagnostics, format)?);
+                if diagnostics.iter().any(lint::Diagnostic::is_error) {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+        },
+    )
 }
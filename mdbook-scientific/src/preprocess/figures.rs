@@ -0,0 +1,174 @@
+//! Markdown images and tables made referenceable figures by a `{#fig:id}` attribute.
+//!
+//! ```markdown
+//! ![Architecture overview](arch.png){#fig:arch}
+//!
+//! | a | b |
+//! |---|---|
+//! | 1 | 2 |
+//! : Measurements {#fig:measured}
+//! ```
+
+use regex::Regex;
+use std::ops::Range;
+
+/// What is numbered as figure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlainFigureKind<'a> {
+    Image {
+        src: &'a str,
+    },
+    /// The markdown table, the rows are its body
+    Table,
+}
+
+/// A figure within the chapter, all ranges are byte offsets into the chapter.
+///
+/// The body and the caption may contain `$` items, everything else of the figure is markup
+/// that is replaced as a whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlainFigure<'a> {
+    /// From the first table row or the image up to the end of the line with the attribute
+    pub range: Range<usize>,
+    /// Table rows, empty for images
+    pub body: Range<usize>,
+    /// Caption text, the image description for images
+    pub caption_range: Range<usize>,
+    pub kind: PlainFigureKind<'a>,
+    pub refer: &'a str,
+    /// Caption as written, for lists of figures and alternative texts
+    pub caption: &'a str,
+}
+
+/// Find all images and tables with a figure attribute, outside of code blocks and of `blocks`.
+///
+/// Images must be on a line of their own, table captions must follow the table directly,
+/// prefixed by `:` or `Table:`. `blocks` are the byte ranges of the `$$` blocks of `text`, which
+/// may contain lines looking like table rows.
+pub fn find_figures<'a>(text: &'a str, blocks: &[Range<usize>]) -> Vec<PlainFigure<'a>> {
+    let image = Regex::new(
+        r#"^\s*!\[(?P<caption>[^\]]*)\]\((?P<src>[^)\s]+)(?:\s+"[^"]*")?\)\{#fig:(?P<refer>[\w.:-]+)\}\s*$"#,
+    )
+    .expect("Regex is valid. qed");
+    let table_caption =
+        Regex::new(r"^\s*(?:Table)?:\s*(?P<caption>.*?)\s*\{#fig:(?P<refer>[\w.:-]+)\}\s*$")
+            .expect("Regex is valid. qed");
+    let is_table_row = |line: &str| line.trim_start().starts_with('|');
+    let in_block = |pos: usize| blocks.iter().any(|block| block.contains(&pos));
+
+    let mut figures = Vec::new();
+    let mut fence: Option<&str> = None;
+    let mut table_start = None;
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        let start = line_start;
+        line_start += line.len();
+        let trimmed = line.trim_start();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+        if let Some(marker) = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(marker))
+        {
+            fence = Some(marker);
+            table_start = None;
+            continue;
+        }
+        if in_block(start) {
+            table_start = None;
+            continue;
+        }
+
+        // the line break stays with the following text
+        let content = line.trim_end_matches(['\r', '\n']);
+        let end = start + content.len();
+        let (body, kind, captures) = if let Some(captures) = image.captures(content) {
+            table_start = None;
+            let kind = PlainFigureKind::Image {
+                src: captures
+                    .name("src")
+                    .expect("Group is not optional. qed")
+                    .as_str(),
+            };
+            (start..start, kind, captures)
+        } else if is_table_row(line) {
+            table_start.get_or_insert(start);
+            continue;
+        } else if let (Some(table), Some(captures)) =
+            (table_start.take(), table_caption.captures(content))
+        {
+            (table..start, PlainFigureKind::Table, captures)
+        } else {
+            continue;
+        };
+
+        let caption = captures
+            .name("caption")
+            .expect("Group is not optional. qed");
+        let refer = captures.name("refer").expect("Group is not optional. qed");
+        figures.push(PlainFigure {
+            range: body.start.min(start)..end,
+            body,
+            caption_range: start + caption.start()..start + caption.end(),
+            kind,
+            refer: &text[start + refer.start()..start + refer.end()],
+            caption: &text[start + caption.start()..start + caption.end()],
+        });
+    }
+    figures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_and_tables_with_attribute_are_figures() {
+        let text = r#"Intro
+![Overview](img/arch.png "tooltip"){#fig:arch}
+![Not a figure](img/other.png)
+
+| a | $x$ |
+|---|---|
+: Measurements of $x$ {#fig:measured}
+
+```markdown
+![Code](x.png){#fig:code}
+```
+$$
+| x | = 1
+: not a caption {#fig:equation}
+$$
+"#;
+        let block = text.find("$$").unwrap()..text.len() - 1;
+        let figures = find_figures(text, &[block]);
+        assert_eq!(
+            figures,
+            vec![
+                PlainFigure {
+                    range: 6..52,
+                    body: 6..6,
+                    caption_range: 8..16,
+                    kind: PlainFigureKind::Image {
+                        src: "img/arch.png"
+                    },
+                    refer: "arch",
+                    caption: "Overview",
+                },
+                PlainFigure {
+                    range: 85..144,
+                    body: 85..107,
+                    caption_range: 109..128,
+                    kind: PlainFigureKind::Table,
+                    refer: "measured",
+                    caption: "Measurements of $x$",
+                },
+            ]
+        );
+        assert_eq!(&text[figures[1].body.clone()], "| a | $x$ |\n|---|---|\n");
+    }
+}
@@ -1,4 +1,150 @@
 use super::*;
+use itertools::Itertools;
+
+/// Escape TeX for a raw html block, which must not contain blank lines.
+fn escape_html_block(tex: &str) -> String {
+    tex.lines()
+        .filter(|line| !line.trim().is_empty())
+        .join("\n")
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Escape TeX so it survives inline markdown processing verbatim.
+fn escape_markdown_inline(tex: &str) -> String {
+    tex.chars().fold(String::with_capacity(tex.len() * 2), |mut acc, c| {
+        if c.is_ascii_punctuation() {
+            acc.push('\\');
+        }
+        acc.push(c);
+        acc
+    })
+}
+
+/// Escape text as character references, which survive markdown processing within html unaltered.
+fn escape_html_text(text: &str) -> String {
+    text.split_whitespace().join(" ").chars().fold(
+        String::with_capacity(text.len() * 2),
+        |mut acc, c| {
+            if c.is_ascii_punctuation() {
+                acc.push_str(&format!("&#{};", c as u32));
+            } else {
+                acc.push(c);
+            }
+            acc
+        },
+    )
+}
+
+/// Embed the rendered replacement, referencing files via `<object>` and inserting markup as is.
+///
+/// Svgs are labelled with `label` for screen readers and as tooltip, pngs carry it as alt text.
+fn format_rendered<'a>(
+    replacement: &Replacement<'a>,
+    class: Option<&str>,
+    label: &str,
+    settings: &Settings<'_>,
+) -> String {
+    // an `<object>` can't follow the text color, `scientific.css` inverts it on dark themes
+    let invert =
+        settings.svg_current_color && matches!(replacement.rendered, Rendered::File { .. });
+    let class = Vec::from_iter(
+        class
+            .into_iter()
+            .chain(invert.then_some("scientific_invert")),
+    );
+    let class = if class.is_empty() {
+        String::new()
+    } else {
+        format!(r#" class="{}""#, class.join(" "))
+    };
+    let label = escape_html_text(label);
+    match replacement.rendered {
+        Rendered::File { ref asset_file, .. } => {
+            format!(
+                r#"<object{class} data="{file}" type="image/svg+xml" role="img" aria-label="{label}" title="{label}">{fallback}</object>"#,
+                class = class,
+                file = asset_file.display(),
+                label = label,
+                fallback = replacement.fallback.as_deref().unwrap_or_default()
+            )
+        }
+        Rendered::InlineSvg(ref svg) => {
+            let style = svg
+                .depth
+                .map(|depth| format!(r#" style="vertical-align: -{:.4}em""#, depth))
+                .unwrap_or_default();
+            format!(
+                r#"<span{class}{style} role="img" aria-label="{label}" title="{label}">{svg}</span>"#,
+                class = class,
+                style = style,
+                label = label,
+                svg = svg.markup
+            )
+        }
+        Rendered::Raster {
+            ref asset_file,
+            extents,
+            ..
+        } => {
+            let style = extents
+                .map(|extents| {
+                    format!(
+                        r#" style="width: {:.4}em; height: {:.4}em; vertical-align: -{:.4}em""#,
+                        extents.width,
+                        extents.height,
+                        extents.depth.unwrap_or_default()
+                    )
+                })
+                .unwrap_or_default();
+            format!(
+                r#"<img{class} src="{file}" alt="{label}"{style} />"#,
+                class = class,
+                file = asset_file.display(),
+                label = label,
+                style = style
+            )
+        }
+        Rendered::MathMl(ref mathml) => mathml.clone(),
+        Rendered::Passthrough => format!(
+            r#"\[ {} \]"#,
+            escape_html_block(replacement.content.trimmed().as_str())
+        ),
+    }
+}
+
+/// A visually hidden copy of the TeX source of a rendered equation, so search finds it.
+///
+/// Screen readers use the label of the svg instead.
+fn format_searchable(replacement: &Replacement<'_>) -> String {
+    match replacement.rendered {
+        Rendered::File { .. } | Rendered::InlineSvg(_) => format!(
+            r#"<span class="scientific_sr_only" aria-hidden="true">{}</span>"#,
+            escape_html_text(replacement.content.trimmed().as_str())
+        ),
+        // the alt text of images is searchable already
+        Rendered::Raster { .. } | Rendered::MathMl(_) | Rendered::Passthrough => String::new(),
+    }
+}
+
+/// Placeholder for an item that failed, so the rest of the book can still be built.
+pub fn format_error(err: &ScientificError, block: bool, renderer: SupportedRenderer) -> String {
+    use SupportedRenderer::*;
+    let msg = err.to_string();
+    let msg = msg.lines().next().unwrap_or_default();
+    match renderer {
+        Html | Markdown | Epub => {
+            let msg = escape_html_block(msg);
+            if block {
+                format!(r#"<div class="scientific_error">⚠ {}</div>"#, msg)
+            } else {
+                format!(r#"<span class="scientific_error">⚠ {}</span>"#, msg)
+            }
+        }
+        Latex | Tectonic => format!("**Error: {}**", escape_markdown_inline(msg)),
+    }
+}
 
 pub fn format_figure<'a>(
     replacement: &Replacement<'a>,
@@ -6,21 +152,28 @@ pub fn format_figure<'a>(
     head_num: &str,
     figures_counter: usize,
     title: &str,
-    renderer: SupportedRenderer,
+    settings: &Settings<'_>,
 ) -> String {
     use SupportedRenderer::*;
-    match renderer {
-        Html | Markdown => {
+    match settings.renderer {
+        Markdown if settings.is_pandoc() => match replacement.rendered {
+            Rendered::File { ref asset_file, .. } => {
+                format!("![{}]({}){{#fig:{}}}", title, asset_file.display(), refer)
+            }
+            _ => format_rendered(replacement, None, title, settings),
+        },
+        Html | Markdown | Epub => {
             format!(
                 r#"<figure id="{refer}" class="figure">
-                    <object data="{file}" type="image/svg+xml"/></object>
-                    <figcaption>Figure {head_num}{figures_counter} {title}</figcaption>
+                    {object}
+                    <figcaption>{label} {head_num}{figures_counter} {title}</figcaption>
                 </figure>"#,
                 refer = refer,
+                label = settings.labels.figure,
                 head_num = head_num,
                 figures_counter = figures_counter,
                 title = title,
-                file = replacement.svg_asset_file.display()
+                object = format_rendered(replacement, None, title, settings)
             )
         }
         Latex | Tectonic => {
@@ -33,26 +186,147 @@ $$"#,
     }
 }
 
+/// Escape the text of a figure caption, substituted `$` items are inserted as they are.
+pub fn escape_caption_text(text: &str, settings: &Settings<'_>) -> String {
+    if settings.renderer.is_html_like() && !settings.is_pandoc() {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    } else {
+        text.to_owned()
+    }
+}
+
+/// A markdown image or table numbered as figure, with its substituted `body` and `caption`.
+///
+/// LaTeX renderers get a `figure` or `table` environment carrying the caption and label.
+pub fn format_plain_figure(
+    figure: &PlainFigure<'_>,
+    head_num: &str,
+    figures_counter: usize,
+    body: &str,
+    caption: &str,
+    settings: &Settings<'_>,
+) -> String {
+    use SupportedRenderer::*;
+    let refer = figure.refer;
+    let label = &settings.labels.figure;
+    match (settings.renderer, figure.kind) {
+        (Markdown, PlainFigureKind::Image { src }) if settings.is_pandoc() => {
+            format!("![{caption}]({src}){{#fig:{refer}}}")
+        }
+        (Markdown, PlainFigureKind::Table) if settings.is_pandoc() => {
+            format!("{body}: {caption} {{#tbl:{refer}}}")
+        }
+        (Html | Markdown | Epub, PlainFigureKind::Image { src }) => {
+            format!(
+                r#"<figure id="{refer}" class="figure">
+                    <img src="{src}" alt="{alt}" />
+                    <figcaption>{label} {head_num}{figures_counter} {caption}</figcaption>
+                </figure>"#,
+                alt = escape_html_text(figure.caption),
+            )
+        }
+        (Html | Markdown | Epub, PlainFigureKind::Table) => {
+            // blank lines around the table, so it is markdown again within the html
+            format!(
+                r#"<figure id="{refer}" class="figure">
+
+{body}
+<figcaption>{label} {head_num}{figures_counter} {caption}</figcaption>
+</figure>"#
+            )
+        }
+        (Latex | Tectonic, PlainFigureKind::Image { src }) => {
+            format!(
+                r#"\begin{{figure}}[htbp]
+\centering
+\includegraphics[width=\textwidth]{{{src}}}
+\caption{{{caption}}}
+\label{{{refer}}}
+\end{{figure}}"#
+            )
+        }
+        (Latex | Tectonic, PlainFigureKind::Table) => {
+            // blank lines around the table, so it is markdown again
+            format!(
+                r#"\begin{{table}}[htbp]
+\centering
+\caption{{{caption}}}
+\label{{{refer}}}
+
+{body}
+\end{{table}}"#
+            )
+        }
+    }
+}
+
+/// A chapter listing numbered items, `label` contains `{}` for the number.
+pub fn format_list(listed: &[&Listed], heading: &str, label: &str) -> String {
+    let mut list = format!("# {}\n\n", heading);
+    for item in listed {
+        list.push_str(&format!(
+            "- [{}]({}) {}\n",
+            label.replace("{}", &item.number),
+            item.link,
+            escape_markdown_inline(&item.caption)
+        ));
+    }
+    list
+}
+
 pub fn format_equation_block<'a>(
     replacement: &Replacement<'a>,
     refer: &str,
     head_num: &str,
     equations_counter: usize,
-    renderer: SupportedRenderer,
+    settings: &Settings<'_>,
 ) -> String {
     use SupportedRenderer::*;
-    match renderer {
-        Html | Markdown => {
+    match settings.renderer {
+        Markdown if settings.is_pandoc() => {
+            let tex = replacement
+                .content
+                .trimmed()
+                .as_str()
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .join("\n");
+            if refer.is_empty() {
+                format!("$${}$$", tex)
+            } else {
+                format!("$${}$$ {{#eq:{}}}", tex, refer)
+            }
+        }
+        Html | Markdown | Epub if matches!(replacement.rendered, Rendered::Passthrough) => {
+            format!(
+                r#"<div id="{refer}" class="equation">
+                    <div class="equation_inner">\[ {tex} \tag{{{head_num}{equations_counter}}} \]</div>
+                </div>"#,
+                refer = refer,
+                head_num = head_num,
+                equations_counter = equations_counter,
+                tex = escape_html_block(replacement.content.trimmed().as_str())
+            )
+        }
+        Html | Markdown | Epub => {
             format!(
                 r#"<div id="{refer}" class="equation">
                     <div class="equation_inner">
-                        <object data="{file}" type="image/svg+xml"></object>
+                        {object}{searchable}
                     </div><span>({head_num}{equations_counter})</span>
                 </div>"#,
                 refer = refer,
                 head_num = head_num,
                 equations_counter = equations_counter,
-                file = replacement.svg_asset_file.display()
+                object = format_rendered(
+                    replacement,
+                    None,
+                    replacement.content.trimmed().as_str(),
+                    settings
+                ),
+                searchable = format_searchable(replacement)
             )
         }
         Latex | Tectonic => {
@@ -67,27 +341,46 @@ $$"#,
 
 pub fn format_equation_inline<'a>(
     replacement: &Replacement<'a>,
-    renderer: SupportedRenderer,
+    settings: &Settings<'_>,
 ) -> String {
     use SupportedRenderer::*;
-    match renderer {
-        Html | Markdown => {
+    match settings.renderer {
+        Markdown if settings.is_pandoc() => {
+            format!("${}$", replacement.content.trimmed().as_str().trim())
+        }
+        Html | Markdown | Epub if matches!(replacement.rendered, Rendered::Passthrough) => {
             format!(
-                r#"<object class="equation_inline" data="{file}" type="image/svg+xml"></object>"#,
-                file = replacement.svg_asset_file.display()
+                r#"\\({}\\)"#,
+                escape_markdown_inline(replacement.content.trimmed().as_str())
             )
         }
+        Html | Markdown | Epub => format!(
+            "{}{}",
+            format_rendered(
+                replacement,
+                Some("equation_inline"),
+                replacement.content.trimmed().as_str(),
+                settings
+            ),
+            format_searchable(replacement)
+        ),
         Latex | Tectonic => {
             format!(r#"${}$"#, replacement.inner_str_or_intermediate())
         }
     }
 }
 
-pub fn format_bib_reference<'a>(refere: &str, title: &str, renderer: SupportedRenderer) -> String {
+/// `target` is the page holding the bibliography, empty for the current page.
+pub fn format_bib_reference<'a>(
+    refere: &str,
+    title: &str,
+    target: &str,
+    renderer: SupportedRenderer,
+) -> String {
     use SupportedRenderer::*;
     match renderer {
-        Html => {
-            format!(r#"<a class="bib_ref" href='bibliography.html#{refere}'>{title}</a>"#)
+        Html | Epub => {
+            format!(r#"<a class="bib_ref" href='{target}#{refere}'>{title}</a>"#)
         }
         Latex | Tectonic | Markdown => {
             format!("$ref:bib:{refere}$")
@@ -95,11 +388,47 @@ pub fn format_bib_reference<'a>(refere: &str, title: &str, renderer: SupportedRe
     }
 }
 
-pub fn format_fig_reference<'a>(refere: &str, title: &str, renderer: SupportedRenderer) -> String {
+/// A pandoc-crossref reference or a pandoc citation.
+pub fn format_pandoc_reference(
+    refere: &str,
+    ref_kind: RefKind,
+    references: &ReferenceTracker,
+) -> String {
+    match ref_kind {
+        RefKind::Bibliography => format!("[@{}]", refere),
+        RefKind::Equation => format!("@eq:{}", refere),
+        RefKind::Figure if references.is_table(refere) => format!("@tbl:{}", refere),
+        RefKind::Figure => format!("@fig:{}", refere),
+    }
+}
+
+/// Hides the previews unless their reference is hovered, so they work without `scientific.css`
+pub const PREVIEW_STYLE: &str =
+    concat!("<style>\n", include_str!("../preview.css"), "</style>\n\n");
+
+/// The hidden image shown when hovering a reference.
+fn format_preview(preview: Option<&str>) -> String {
+    preview
+        .map(|src| {
+            format!(
+                r#"<span class="scientific_preview"><img src="{}" alt="" loading="lazy"></span>"#,
+                src
+            )
+        })
+        .unwrap_or_default()
+}
+
+pub fn format_fig_reference(
+    refere: &str,
+    title: &str,
+    preview: Option<&str>,
+    renderer: SupportedRenderer,
+) -> String {
     use SupportedRenderer::*;
     match renderer {
-        Html => {
-            format!(r#"<a class="fig_ref" href='#{refere}'>{title}</a>"#)
+        Html | Epub => {
+            let preview = format_preview(preview);
+            format!(r#"<a class="fig_ref" href='#{refere}'>{title}{preview}</a>"#)
         }
         Latex | Tectonic | Markdown => {
             format!("$ref:fig:{refere}$")
@@ -107,14 +436,34 @@ pub fn format_fig_reference<'a>(refere: &str, title: &str, renderer: SupportedRe
     }
 }
 
-pub fn format_equ_reference<'a>(refere: &str, title: &str, renderer: SupportedRenderer) -> String {
+pub fn format_equ_reference(
+    refere: &str,
+    title: &str,
+    preview: Option<&str>,
+    settings: &Settings<'_>,
+) -> String {
     use SupportedRenderer::*;
-    match renderer {
-        Html => {
-            format!(r#"<a class="equ_ref" href='#{refere}'>Eq. ({title})</a>"#)
+    match settings.renderer {
+        Html | Epub => {
+            let label = &settings.labels.equation;
+            let preview = format_preview(preview);
+            format!(r#"<a class="equ_ref" href='#{refere}'>{label} ({title}){preview}</a>"#)
         }
         Latex | Tectonic | Markdown => {
             format!("$ref:equ:{refere}$")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_survive_markdown_and_html() {
+        assert_eq!(
+            escape_html_text("a_1 <  \n\"b\" * 2"),
+            "a&#95;1 &#60; &#34;b&#34; &#42; 2"
+        );
+    }
+}
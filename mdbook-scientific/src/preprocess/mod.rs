@@ -1,213 +1,1277 @@
-use fs_err as fs;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
 
-use crate::errors::{Result, ScientificError};
-use crate::fragments;
+use crate::bibliography::BibliographyConfig;
+use crate::config;
+use crate::engine::TexEngine;
+use crate::errors::{ItemError, Result, ScientificError};
+use crate::fragments::{self, Fragment};
+use crate::labels::Labels;
+use crate::template::FragmentTemplate;
 use crate::types::*;
 use mathyank::iter_over_dollar_encompassed_blocks;
 use mathyank::*;
+use mdbook_boilerplate::Table;
 
+mod figures;
 mod format;
+pub use self::figures::*;
 pub use self::format::*;
 
-pub fn replace_blocks(
-    fragment_path: impl AsRef<Path>,
-    asset_path: impl AsRef<Path>,
-    source: &str,
-    chapter_number: &str,
-    chapter_name: &str,
-    chapter_path: impl AsRef<Path>,
-    renderer: SupportedRenderer,
-    used_fragments: &mut Vec<PathBuf>,
-    references: &mut ReferenceTracker,
-) -> Result<String> {
-    let chapter_path = chapter_path.as_ref();
-    let fragment_path = fragment_path.as_ref();
-    fs::create_dir_all(fragment_path)?;
+/// Settings that apply to all chapters alike
+#[derive(Debug, Clone, Copy)]
+pub struct Settings<'a> {
+    /// Canonical book root, data files of plots are named relative to it
+    pub root: &'a Path,
+    pub fragment_path: &'a Path,
+    pub asset_path: &'a Path,
+    pub renderer: SupportedRenderer,
+    pub math: MathMode,
+    pub engine: TexEngine,
+    pub template: &'a FragmentTemplate,
+    pub svg_embedding: SvgEmbedding,
+    pub markdown_flavour: MarkdownFlavour,
+    /// Inlined svgs follow the text color of the theme, referenced ones are inverted on dark
+    /// themes
+    pub svg_current_color: bool,
+    /// Referenced svgs carry a MathML fallback
+    pub mathml_fallback: bool,
+    /// Fragments are rasterized to png at this resolution, for epub
+    pub raster_dpi: Option<u32>,
+    /// Page holding the bibliography, empty if it's the citing page
+    pub bib_link_target: &'a str,
+    /// References show the rendered equation or figure when hovered
+    pub reference_previews: bool,
+    pub labels: &'a Labels,
+}
 
-    let asset_path = asset_path.as_ref();
+impl<'a> Settings<'a> {
+    /// Read the rendering options of the book from `cfg`, as they apply to `renderer`.
+    pub fn from_cfg(
+        cfg: &Table,
+        renderer: SupportedRenderer,
+        root: &'a Path,
+        fragment_path: &'a Path,
+        asset_path: &'a Path,
+        template: &'a FragmentTemplate,
+        labels: &'a Labels,
+    ) -> Result<Self> {
+        let markdown_flavour =
+            config::get_parsed::<MarkdownFlavour>(cfg, "markdown_flavour")?.unwrap_or_default();
+        // pandoc gets TeX math and references to image files
+        let pandoc =
+            renderer == SupportedRenderer::Markdown && markdown_flavour == MarkdownFlavour::Pandoc;
+        // e-readers get png images or MathML, but no svg objects
+        let epub = renderer == SupportedRenderer::Epub;
+        let epub_math = config::get_parsed::<EpubMath>(cfg, "epub_math")?.unwrap_or_default();
+        let math = match epub_math {
+            _ if pandoc => MathMode::Passthrough,
+            EpubMath::Png if epub => MathMode::Svg,
+            EpubMath::MathMl if epub => MathMode::MathMl,
+            _ => config::get_parsed::<MathMode>(cfg, "math")?.unwrap_or_default(),
+        };
+        let raster_dpi = if epub {
+            Some(config::get_integer_in(cfg, "epub_dpi", 1..=2400)?.unwrap_or(150) as u32)
+        } else {
+            None
+        };
+        log::info!("Using math mode: {:?}", math);
+        let engine = config::get_parsed::<TexEngine>(cfg, "engine")?.unwrap_or_default();
+        let svg_embedding = if pandoc || epub {
+            SvgEmbedding::Object
+        } else {
+            config::get_parsed::<SvgEmbedding>(cfg, "svg_embedding")?.unwrap_or_default()
+        };
+        let svg_current_color = config::get_bool(cfg, "svg_current_color")?.unwrap_or(false);
 
-    let iter = dollar_split_tags_iter(source);
-    let s = iter_over_dollar_encompassed_blocks(source, iter)
-        .map(|tagged| match tagged {
-            Tagged::Keep(content) => Ok(content.as_str().to_owned()),
-            Tagged::Replace(content) => {
-                let replaced = if content.start_del.is_block() || content.end_del.is_block() {
-                    log::debug!("Found block");
-                    transform_block_as_needed(
-                        &content,
-                        fragment_path,
-                        asset_path,
-                        &chapter_number,
-                        &chapter_name,
-                        &chapter_path,
-                        references,
-                        used_fragments,
-                        renderer,
-                    )
-                } else {
-                    log::debug!("Found inline");
-                    transform_inline_as_needed(
-                        &content,
-                        fragment_path,
-                        asset_path,
-                        chapter_number,
-                        chapter_name,
-                        chapter_path,
-                        references,
-                        used_fragments,
-                        renderer,
-                    )
-                };
-                replaced
-            }
+        Ok(Self {
+            root,
+            fragment_path,
+            asset_path,
+            renderer,
+            math,
+            engine,
+            template,
+            svg_embedding,
+            markdown_flavour,
+            svg_current_color,
+            mathml_fallback: config::get_bool(cfg, "mathml_fallback")?.unwrap_or(false),
+            raster_dpi,
+            bib_link_target: BibliographyConfig::from_cfg(cfg)?.placement.link_target(),
+            reference_previews: config::get_bool(cfg, "reference_previews")?.unwrap_or(false),
+            labels,
         })
-        .collect::<Result<Vec<String>>>()?
-        .into_iter()
-        .collect::<String>();
-    Ok(s)
+    }
+
+    /// Writing markdown for pandoc rather than html snippets
+    pub fn is_pandoc(&self) -> bool {
+        self.renderer == SupportedRenderer::Markdown
+            && self.markdown_flavour == MarkdownFlavour::Pandoc
+    }
 }
 
-fn transform_block_as_needed<'a>(
-    content: &Content<'a>,
-    fragment_path: impl AsRef<Path>,
-    asset_path: impl AsRef<Path>,
-    chapter_number: &str,
-    chapter_name: &str,
-    _chapter_path: impl AsRef<Path>,
-    references: &mut ReferenceTracker,
-    used_fragments: &mut Vec<PathBuf>,
-    renderer: SupportedRenderer,
-) -> Result<String> {
-    let fragment_path = fragment_path.as_ref();
-    let asset_path = asset_path.as_ref();
+/// The chapter that is being processed
+#[derive(Debug, Clone, Copy)]
+pub struct ChapterInfo<'a> {
+    pub number: &'a str,
+    /// Source file relative to the book root, as shown in error messages
+    pub path: &'a Path,
+    /// Source file relative to the source directory, as linked to from other chapters
+    pub link: &'a Path,
+}
 
-    let mut figures_counter = 0;
-    let mut equations_counter = 0;
+impl ChapterInfo<'_> {
+    /// Anchor of a figure without label, to link it from the list of figures
+    fn figure_anchor(&self, counter: usize) -> String {
+        format!("scientific_figure_{}{}", self.number, counter).replace('.', "_")
+    }
 
-    let mut add_object = move |replacement: &mut Replacement<'_>,
-                               refer: Option<&str>,
-                               title: Option<&str>|
-          -> String {
-        let fragment_file = replacement.svg_fragment_file.as_path();
-        used_fragments.push(fragment_file.to_owned());
-
-        if let Some(title) = title {
-            let refer = refer.unwrap_or("unknown var");
-            figures_counter += 1;
-            references.add(
-                refer,
-                format!("Figure {}{}", chapter_number, figures_counter),
-            );
+    fn listed(&self, kind: RefKind, counter: usize, caption: &str, anchor: &str) -> Listed {
+        Listed {
+            kind,
+            number: format!("{}{}", self.number, counter),
+            caption: caption.to_owned(),
+            link: format!("{}#{}", self.link.display(), anchor),
+        }
+    }
+}
 
-            format_figure(
-                replacement,
-                refer,
-                &chapter_number,
-                figures_counter,
-                title,
-                renderer,
-            )
-        } else if let Some(refer) = refer.filter(|s| !s.is_empty()) {
-            equations_counter += 1;
-            references.add(refer, format!("{}{}", chapter_number, equations_counter));
-            format_equation_block(
-                replacement,
-                refer,
-                &chapter_number,
-                equations_counter,
-                renderer,
+/// What the substitution of a chapter depends on, besides its source and the settings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChapterUses {
+    /// Store names of the fragments of the chapter
+    pub fragments: BTreeSet<String>,
+    /// Labels the chapter references
+    pub references: BTreeSet<String>,
+}
+
+impl ChapterUses {
+    /// Hash over everything the substituted chapter depends on, as key of the chapter cache.
+    ///
+    /// `fragment_inputs` are the provenance of all fragments by store name, their input hashes
+    /// and the versions of the tools that rendered them, so a re-rendered fragment, i.e. after a
+    /// tool update, invalidates the chapters that inline it.
+    pub fn key(
+        &self,
+        source: &str,
+        settings: &Settings<'_>,
+        chapter: &ChapterInfo<'_>,
+        references: &ReferenceTracker,
+        fragment_inputs: &HashMap<String, String>,
+        failed_fragments: &HashSet<PathBuf>,
+    ) -> String {
+        let failed = |fragment_file: &Path| failed_fragments.contains(fragment_file);
+        let fragments = self.fragments.iter().map(|name| {
+            let inputs = fragment_inputs.get(name).map(String::as_str);
+            let failed = failed(&settings.fragment_path.join(name));
+            format!("{}\0{:?}\0{}", name, inputs, failed)
+        });
+        let references = self.references.iter().map(|label| {
+            let failed = match references.preview(label) {
+                Some(Preview::Fragment { fragment_file, .. }) => failed(fragment_file),
+                _ => false,
+            };
+            format!("{}\0{}\0{}", label, references.resolution(label), failed)
+        });
+        fragments::hash(
+            Vec::from_iter(
+                [
+                    format!("{:?}", settings),
+                    format!("{:?}", chapter),
+                    source.to_owned(),
+                ]
+                .into_iter()
+                .chain(fragments)
+                .chain(references),
             )
+            .join("\0"),
+        )
+    }
+}
+
+/// A `$` or `$$` delimited item with its number and fragment determined, but nothing rendered yet.
+enum Planned<'a> {
+    Keep(&'a str),
+    /// A markdown image or table with a figure attribute, its parts follow
+    Figure {
+        figure: PlainFigure<'a>,
+        counter: usize,
+    },
+    /// The following items belong to this part of the current figure
+    FigurePart(FigurePart),
+    Reference {
+        content: Content<'a>,
+        ref_kind: RefKind,
+        refere: String,
+    },
+    Inline {
+        content: Content<'a>,
+        fragment: Option<Fragment>,
+    },
+    Block {
+        content: Content<'a>,
+        kind: EquBlockKind,
+        refer: Option<String>,
+        title: Option<String>,
+        /// The figure or equation number within the chapter
+        counter: usize,
+        fragment: Option<Fragment>,
+    },
+    /// Could not be parsed, reported and replaced by a placeholder during substitution
+    Invalid {
+        content: Content<'a>,
+        block: bool,
+        source: ScientificError,
+    },
+}
+
+/// A section of a markdown image or table figure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FigurePart {
+    /// Table rows
+    Body,
+    Caption,
+    /// Replaced by the figure as a whole
+    Markup,
+    End,
+}
+
+/// Take the next figure mark, numbering the figure if it is the start of one.
+fn take_mark<'a>(
+    marks: &mut VecDeque<(usize, Planned<'a>)>,
+    figures_counter: &mut usize,
+) -> Planned<'a> {
+    let (_, mut planned) = marks.pop_front().expect("Only called with marks left. qed");
+    if let Planned::Figure {
+        ref mut counter, ..
+    } = planned
+    {
+        *figures_counter += 1;
+        *counter = *figures_counter;
+    }
+    planned
+}
+
+/// Split the chapter into its items, numbering blocks and determining the fragments to render.
+///
+/// Does not render or resolve anything, so it yields the same result in every phase.
+fn plan<'a>(
+    source: &'a str,
+    settings: &Settings<'_>,
+    chapter: &ChapterInfo<'_>,
+) -> Vec<Planned<'a>> {
+    let mut figures_counter = 0;
+    let mut equations_counter = 0;
+
+    let fragment = |content: &Content<'_>, kind: EquBlockKind, zoom: f32| {
+        let in_process = kind == EquBlockKind::Equation
+            && settings.math.is_in_process()
+            && settings.renderer.is_html_like();
+        if in_process {
+            Ok(None)
         } else {
-            format_equation_block(
-                replacement,
-                "",
-                &chapter_number,
-                equations_counter,
-                renderer,
+            Fragment::new(
+                settings,
+                content,
+                kind,
+                zoom,
+                Origin::new(chapter.path, source, content),
             )
+            .map(Some)
         }
     };
 
-    let BlockEqu {
-        kind, refer, title, ..
-    } = BlockEqu::try_from(content)?;
+    let iter = dollar_split_tags_iter(source);
+    let tagged = Vec::from_iter(iter_over_dollar_encompassed_blocks(source, iter));
 
-    let mut replacement = match kind {
-        EquBlockKind::Latex => fragments::parse_latex(fragment_path, asset_path, &content)?,
-        EquBlockKind::GnuPlot => fragments::parse_gnuplot(fragment_path, asset_path, &content)?,
-        EquBlockKind::GnuPlotOnly => {
-            fragments::parse_gnuplot_only(fragment_path, asset_path, &content)?
+    // figures are found on the whole chapter, their tables and captions may contain `$` items
+    let blocks = Vec::from_iter(tagged.iter().filter_map(|tagged| match tagged {
+        Tagged::Replace(content) if content.start_del.is_block() || content.end_del.is_block() => {
+            Some(content.byte_range.clone())
         }
-        EquBlockKind::Equation => fragments::generate_replacement_file_from_template(
-            fragment_path,
-            asset_path,
-            &content,
-            1.6,
-            chapter_number,
-            chapter_name,
-        )?,
-    };
-    let replacement = add_object(&mut replacement, refer, title);
+        _ => None,
+    }));
+    let mut marks = VecDeque::new();
+    for figure in find_figures(source, &blocks) {
+        let (body, caption, end) = (
+            figure.body.clone(),
+            figure.caption_range.clone(),
+            figure.range.end,
+        );
+        marks.push_back((figure.range.start, Planned::Figure { figure, counter: 0 }));
+        if !body.is_empty() {
+            marks.push_back((body.start, Planned::FigurePart(FigurePart::Body)));
+            marks.push_back((body.end, Planned::FigurePart(FigurePart::Markup)));
+        }
+        marks.push_back((caption.start, Planned::FigurePart(FigurePart::Caption)));
+        marks.push_back((caption.end, Planned::FigurePart(FigurePart::Markup)));
+        marks.push_back((end, Planned::FigurePart(FigurePart::End)));
+    }
 
-    let (emoji, desc) = kind.as_emoji_w_desc();
-    log::info!("{emoji} Found block {desc}");
-    Ok(replacement)
+    let mut planned = Vec::new();
+    for tagged in tagged {
+        let range = match tagged {
+            Tagged::Keep(ref content) | Tagged::Replace(ref content) => content.byte_range.clone(),
+        };
+        while marks.front().is_some_and(|(at, _)| *at <= range.start) {
+            planned.push(take_mark(&mut marks, &mut figures_counter));
+        }
+        let item = match tagged {
+            Tagged::Keep(_) => {
+                // split the text at the figures and their parts
+                let mut start = range.start;
+                while let Some(at) = marks
+                    .front()
+                    .map(|(at, _)| *at)
+                    .filter(|at| *at < range.end)
+                {
+                    if start < at {
+                        planned.push(Planned::Keep(&source[start..at]));
+                        start = at;
+                    }
+                    planned.push(take_mark(&mut marks, &mut figures_counter));
+                }
+                if start < range.end {
+                    planned.push(Planned::Keep(&source[start..range.end]));
+                }
+                continue;
+            }
+            Tagged::Replace(content)
+                if content.start_del.is_block() || content.end_del.is_block() =>
+            {
+                log::debug!("Found block");
+                let BlockEqu {
+                    kind, refer, title, ..
+                } = match BlockEqu::try_from(&content) {
+                    Ok(block) => block,
+                    Err(err) => {
+                        planned.push(Planned::Invalid {
+                            content: content.clone(),
+                            block: true,
+                            source: err.into(),
+                        });
+                        continue;
+                    }
+                };
+                let refer = refer.map(str::to_owned);
+                let title = title.map(str::to_owned);
+
+                let counter = if title.is_some() {
+                    figures_counter += 1;
+                    figures_counter
+                } else if refer.as_deref().filter(|s| !s.is_empty()).is_some() {
+                    equations_counter += 1;
+                    equations_counter
+                } else {
+                    equations_counter
+                };
+
+                let zoom = match kind {
+                    EquBlockKind::Equation => 1.6,
+                    _ => 1.0,
+                };
+                match fragment(&content, kind, zoom) {
+                    Ok(fragment) => Planned::Block {
+                        content,
+                        kind,
+                        refer,
+                        title,
+                        counter,
+                        fragment,
+                    },
+                    Err(source) => Planned::Invalid {
+                        content,
+                        block: true,
+                        source,
+                    },
+                }
+            }
+            Tagged::Replace(content) => {
+                log::debug!("Found inline");
+                match Inline::try_from(&content) {
+                    Ok(Inline::Reference(Reference { ref_kind, refere })) => {
+                        let refere = refere.to_owned();
+                        Planned::Reference {
+                            content,
+                            ref_kind,
+                            refere,
+                        }
+                    }
+                    Ok(Inline::Equation(_equ)) => {
+                        match fragment(&content, EquBlockKind::Equation, 1.3) {
+                            Ok(fragment) => Planned::Inline { content, fragment },
+                            Err(source) => Planned::Invalid {
+                                content,
+                                block: false,
+                                source,
+                            },
+                        }
+                    }
+                    Err(err) => Planned::Invalid {
+                        content: content.clone(),
+                        block: false,
+                        source: err.into(),
+                    },
+                }
+            }
+        };
+        planned.push(item);
+    }
+    while !marks.is_empty() {
+        planned.push(take_mark(&mut marks, &mut figures_counter));
+    }
+    planned
 }
 
-/// Transform an inline equation such as
-/// ```text
-/// Hello sir, I am an equation $a_b(x) = b \times x$ which should render inline!
-/// ```
-/// Can also be used to reference block equations via `$ref:hello$` where `hello` 'd be the block equation name.
-fn transform_inline_as_needed<'a>(
-    content: &Content<'a>,
-    fragment_path: impl AsRef<Path>,
-    asset_path: impl AsRef<Path>,
-    chapter_number: &str,
-    chapter_name: &str,
-    _chapter_path: impl AsRef<Path>,
+/// Collect phase: register all labels of a chapter and gather the fragments it needs rendered.
+///
+/// Fragments are shared by all chapters, returns those and the labels this chapter uses.
+/// Labels defined before are added to `errors`.
+pub fn collect_fragments(
+    source: &str,
+    settings: &Settings<'_>,
+    chapter: &ChapterInfo<'_>,
+    fragments: &mut BTreeMap<PathBuf, Fragment>,
     references: &mut ReferenceTracker,
+    errors: &mut Vec<ItemError>,
+) -> ChapterUses {
+    let mut uses = ChapterUses::default();
+    let mut add = |fragment: Fragment, uses: &mut ChapterUses| {
+        uses.fragments.insert(fragment.store_name());
+        fragments
+            .entry(fragment.fragment_file.clone())
+            .or_insert(fragment);
+    };
+    for planned in plan(source, settings, chapter) {
+        match planned {
+            Planned::Keep(_) | Planned::FigurePart(_) | Planned::Invalid { .. } => continue,
+            Planned::Reference { refere, .. } => {
+                uses.references.insert(refere);
+            }
+            Planned::Figure { figure, counter } => {
+                let start = figure.range.start;
+                let origin = Origin::at(chapter.path, source, start..start);
+                let title = format!("{} {}{}", settings.labels.figure, chapter.number, counter);
+                if let Err(source) = references.define(figure.refer, title, origin.clone()) {
+                    errors.push(ItemError { origin, source });
+                    continue;
+                }
+                references.list(chapter.listed(
+                    RefKind::Figure,
+                    counter,
+                    figure.caption,
+                    figure.refer,
+                ));
+                if let PlainFigureKind::Table { .. } = figure.kind {
+                    references.add_table(figure.refer);
+                }
+                if let PlainFigureKind::Image { src } = figure.kind {
+                    let src = match chapter.link.parent() {
+                        Some(dir) if !src.contains("://") => dir.join(src).display().to_string(),
+                        _ => src.to_owned(),
+                    };
+                    references.add_preview(figure.refer, Preview::Image(src));
+                }
+            }
+            Planned::Inline { fragment, .. } => {
+                if let Some(fragment) = fragment {
+                    add(fragment, &mut uses);
+                }
+            }
+            Planned::Block {
+                content,
+                refer,
+                title,
+                counter,
+                fragment,
+                ..
+            } => {
+                let refer = refer.as_deref().filter(|s| !s.is_empty());
+                let (label, number, listed) = match (&title, refer) {
+                    (Some(title), _) => {
+                        let anchor = refer
+                            .map(str::to_owned)
+                            .unwrap_or_else(|| chapter.figure_anchor(counter));
+                        let number =
+                            format!("{} {}{}", settings.labels.figure, chapter.number, counter);
+                        let listed = chapter.listed(RefKind::Figure, counter, title, &anchor);
+                        (refer, number, Some(listed))
+                    }
+                    (None, Some(refer)) => {
+                        let number = format!("{}{}", chapter.number, counter);
+                        let listed = chapter.listed(RefKind::Equation, counter, refer, refer);
+                        (Some(refer), number, Some(listed))
+                    }
+                    (None, None) => (None, String::new(), None),
+                };
+                // a duplicate is still rendered, but neither listed nor previewed
+                let defined = match label {
+                    Some(label) => {
+                        let origin = Origin::new(chapter.path, source, &content);
+                        match references.define(label, number, origin.clone()) {
+                            Ok(()) => true,
+                            Err(source) => {
+                                errors.push(ItemError { origin, source });
+                                false
+                            }
+                        }
+                    }
+                    None => true,
+                };
+                let label = label.filter(|_| defined);
+                if let Some(listed) = listed.filter(|_| defined) {
+                    references.list(listed);
+                }
+                if let (Some(label), Some(fragment)) = (label, fragment.as_ref()) {
+                    references.add_preview(
+                        label,
+                        Preview::Fragment {
+                            fragment_file: fragment.fragment_file.clone(),
+                            asset_file: fragment.asset_file.clone(),
+                        },
+                    );
+                }
+                if let Some(fragment) = fragment {
+                    add(fragment, &mut uses);
+                }
+            }
+        }
+    }
+    uses
+}
+
+/// Substitution phase: replace all items of a chapter with their rendered counterparts.
+///
+/// All fragments must have been rendered and all labels registered at this point. Items that
+/// fail are added to `errors` and replaced by a placeholder, fragments in `failed_fragments`
+/// were reported when rendering already.
+pub fn replace_blocks(
+    source: &str,
+    settings: &Settings<'_>,
+    chapter: &ChapterInfo<'_>,
     used_fragments: &mut Vec<PathBuf>,
-    renderer: SupportedRenderer,
+    references: &ReferenceTracker,
+    failed_fragments: &HashSet<PathBuf>,
+    errors: &mut Vec<ItemError>,
+) -> String {
+    let mut replace = |content: &Content<'_>, block: bool, replaced: Result<String>| {
+        replaced.unwrap_or_else(|err| {
+            let placeholder = format_error(&err, block, settings.renderer);
+            // failed fragments were reported at their first occurrence already
+            if !matches!(err, ScientificError::FragmentFailed) {
+                errors.push(ItemError {
+                    origin: Origin::new(chapter.path, source, content),
+                    source: err,
+                });
+            }
+            placeholder
+        })
+    };
+
+    let mut output = String::new();
+    // the figure being assembled, with its substituted table and caption
+    let mut figure = None;
+    let mut part = FigurePart::Markup;
+    let mut previewed = false;
+    for planned in plan(source, settings, chapter) {
+        let text = matches!(planned, Planned::Keep(_));
+        let substituted = match planned {
+            Planned::Keep(s) => s.to_owned(),
+            Planned::Figure {
+                figure: started,
+                counter,
+            } => {
+                figure = Some((started, counter, String::new(), String::new()));
+                part = FigurePart::Markup;
+                continue;
+            }
+            Planned::FigurePart(FigurePart::End) => {
+                if let Some((figure, counter, body, caption)) = figure.take() {
+                    output.push_str(&format_plain_figure(
+                        &figure,
+                        chapter.number,
+                        counter,
+                        &body,
+                        &caption,
+                        settings,
+                    ));
+                }
+                continue;
+            }
+            Planned::FigurePart(next) => {
+                part = next;
+                continue;
+            }
+            Planned::Invalid {
+                content,
+                block,
+                source,
+            } => replace(&content, block, Err(source)),
+            Planned::Reference {
+                content,
+                ref_kind,
+                refere,
+            } => {
+                let wants_preview =
+                    settings.reference_previews && settings.renderer == SupportedRenderer::Html;
+                let preview = references
+                    .preview(&refere)
+                    .filter(|_| wants_preview)
+                    .filter(|preview| match preview {
+                        // failed fragments have no file to show
+                        Preview::Fragment { fragment_file, .. } => {
+                            !failed_fragments.contains(fragment_file)
+                        }
+                        Preview::Image(_) => true,
+                    });
+                previewed |= preview.is_some();
+                // inlined svgs are not copied to the assets otherwise
+                if let Some(Preview::Fragment { fragment_file, .. }) = preview {
+                    used_fragments.push(fragment_file.clone());
+                }
+                let preview = preview.map(|preview| preview.src(chapter.link));
+                let replaced = transform_reference(
+                    &content,
+                    ref_kind,
+                    &refere,
+                    preview.as_deref(),
+                    settings,
+                    references,
+                );
+                replace(&content, false, replaced)
+            }
+            Planned::Inline { content, fragment } => {
+                let replaced = substitute(
+                    &content,
+                    fragment,
+                    settings,
+                    chapter,
+                    false,
+                    failed_fragments,
+                )
+                .map(|replacement| {
+                    used_fragments.extend(replacement.rendered.fragment_file().map(Path::to_owned));
+                    format_equation_inline(&replacement, settings)
+                });
+                replace(&content, false, replaced)
+            }
+            Planned::Block {
+                content,
+                kind,
+                refer,
+                title,
+                counter,
+                fragment,
+            } => {
+                let replaced = substitute(
+                    &content,
+                    fragment,
+                    settings,
+                    chapter,
+                    true,
+                    failed_fragments,
+                )
+                .map(|replacement| {
+                    used_fragments.extend(replacement.rendered.fragment_file().map(Path::to_owned));
+
+                    let (emoji, desc) = kind.as_emoji_w_desc();
+                    log::info!("{emoji} Found block {desc}");
+                    if let Some(title) = title {
+                        let refer = match refer.as_deref().filter(|s| !s.is_empty()) {
+                            Some(refer) => refer.to_owned(),
+                            None => chapter.figure_anchor(counter),
+                        };
+                        format_figure(
+                            &replacement,
+                            &refer,
+                            chapter.number,
+                            counter,
+                            &title,
+                            settings,
+                        )
+                    } else {
+                        let refer = refer.as_deref().filter(|s| !s.is_empty()).unwrap_or("");
+                        format_equation_block(
+                            &replacement,
+                            refer,
+                            chapter.number,
+                            counter,
+                            settings,
+                        )
+                    }
+                });
+                replace(&content, true, replaced)
+            }
+        };
+        match (figure.as_mut(), part) {
+            (None, _) => output.push_str(&substituted),
+            (Some((_, _, body, _)), FigurePart::Body) => body.push_str(&substituted),
+            (Some((_, _, _, caption)), FigurePart::Caption) if text => {
+                caption.push_str(&escape_caption_text(&substituted, settings))
+            }
+            (Some((_, _, _, caption)), FigurePart::Caption) => caption.push_str(&substituted),
+            (Some(_), _) => {}
+        }
+    }
+    if previewed {
+        output.insert_str(0, PREVIEW_STYLE);
+    }
+    output
+}
+
+/// The replacement of a rendered fragment or an in-process equation.
+fn substitute<'a>(
+    content: &Content<'a>,
+    fragment: Option<Fragment>,
+    settings: &Settings<'_>,
+    chapter: &ChapterInfo<'_>,
+    block: bool,
+    failed_fragments: &HashSet<PathBuf>,
+) -> Result<Replacement<'a>> {
+    match fragment {
+        Some(fragment) if failed_fragments.contains(&fragment.fragment_file) => {
+            Err(ScientificError::FragmentFailed)
+        }
+        Some(fragment) => {
+            let mut replacement = fragment.replacement(content)?;
+            let wants_fallback = settings.mathml_fallback
+                && settings.renderer.is_html_like()
+                && fragment.kind == EquBlockKind::Equation
+                && matches!(replacement.rendered, Rendered::File { .. });
+            if wants_fallback {
+                // the svg is authoritative, an equation latex2mathml can't handle has no fallback
+                replacement.fallback = fragments::equation_to_mathml(content, block)
+                    .map_err(|err| log::debug!("No MathML fallback: {}", err))
+                    .ok();
+            }
+            if let Rendered::Raster {
+                ref mut asset_file, ..
+            } = replacement.rendered
+            {
+                // e-readers resolve images relative to the chapter
+                *asset_file = Path::new(&path_to_root(chapter.link)).join(&*asset_file);
+            }
+            Ok(replacement)
+        }
+        None => fragments::generate_in_process(content, settings.math, block),
+    }
+}
+
+/// Transform a reference such as `$ref:equ:hello$`, where `hello` 'd be the block equation name.
+fn transform_reference(
+    content: &Content<'_>,
+    ref_kind: RefKind,
+    refere: &str,
+    preview: Option<&str>,
+    settings: &Settings<'_>,
+    references: &ReferenceTracker,
 ) -> Result<String> {
-    let fragment_path = fragment_path.as_ref();
-    let asset_path = asset_path.as_ref();
     let lineno = content.start.lineno;
+    let renderer = settings.renderer;
+    if settings.is_pandoc() && ref_kind == RefKind::Bibliography {
+        // pandoc resolves citations itself
+        return Ok(format_pandoc_reference(refere, ref_kind, references));
+    }
+    let title = references
+        .get(refere)
+        .ok_or_else(|| ScientificError::InvalidReference {
+            to: refere.to_owned(),
+            lineno,
+            did_you_mean: references.suggest(refere).map(str::to_owned),
+        })?;
+    let title = title.as_ref();
+    let replacement = match ref_kind {
+        _ if settings.is_pandoc() => format_pandoc_reference(refere, ref_kind, references),
+        RefKind::Bibliography => {
+            format_bib_reference(refere, title, settings.bib_link_target, renderer)
+        }
+        RefKind::Figure => format_fig_reference(refere, title, preview, renderer),
+        RefKind::Equation => format_equ_reference(refere, title, preview, settings),
+    };
 
-    use mathyank::*;
-    match Inline::try_from(content)? {
-        Inline::Reference(reference) => {
-            let Reference { ref_kind, refere } = reference;
-            let title = references
-                .get(refere)
-                .ok_or(ScientificError::InvalidReference {
-                    to: refere.to_owned(),
-                    lineno,
-                })?;
-            let title = title.as_ref();
-            let replacement = match ref_kind {
-                RefKind::Bibliography => format_bib_reference(refere, title, renderer),
-                RefKind::Figure => format_fig_reference(refere, title, renderer),
-                RefKind::Equation => format_equ_reference(refere, title, renderer),
-            };
+    let (emoji, desc) = ref_kind.as_emoji_w_desc();
+    log::info!("{emoji} Found reference {desc}");
+    Ok(replacement)
+}
 
-            let (emoji, desc) = ref_kind.as_emoji_w_desc();
-            log::info!("{emoji} Found reference {desc}");
-            Ok(replacement)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+    use fs_err as fs;
+    use std::sync::OnceLock;
+
+    fn settings() -> Settings<'static> {
+        static TEMPLATE: OnceLock<FragmentTemplate> = OnceLock::new();
+        static LABELS: OnceLock<Labels> = OnceLock::new();
+        Settings {
+            root: Path::new(""),
+            fragment_path: Path::new("fragments"),
+            asset_path: Path::new("assets"),
+            renderer: SupportedRenderer::Html,
+            math: MathMode::Passthrough,
+            engine: TexEngine::default(),
+            template: TEMPLATE.get_or_init(FragmentTemplate::default),
+            svg_embedding: SvgEmbedding::default(),
+            markdown_flavour: MarkdownFlavour::default(),
+            svg_current_color: false,
+            mathml_fallback: false,
+            raster_dpi: None,
+            bib_link_target: "",
+            reference_previews: false,
+            labels: LABELS.get_or_init(Labels::default),
         }
-        Inline::Equation(_equ) => {
-            let replacement = fragments::generate_replacement_file_from_template(
-                fragment_path,
-                asset_path,
-                &content,
-                1.3,
-                chapter_number,
-                chapter_name,
-            )?;
-            let res = format_equation_inline(&replacement, renderer);
-            used_fragments.push(replacement.svg_fragment_file);
-            Ok(res)
+    }
+
+    /// The first chapter, `src/ch1.md`
+    fn chapter() -> ChapterInfo<'static> {
+        ChapterInfo {
+            number: "1.",
+            path: Path::new("src/ch1.md"),
+            link: Path::new("ch1.md"),
         }
     }
+
+    /// Run both phases on `source` as the only chapter
+    fn render(source: &str, settings: &Settings) -> (String, ReferenceTracker, Vec<ItemError>) {
+        render_in(
+            &chapter(),
+            source,
+            settings,
+            &mut Vec::new(),
+            &HashSet::new(),
+        )
+    }
+
+    fn render_in(
+        chapter: &ChapterInfo,
+        source: &str,
+        settings: &Settings,
+        used_fragments: &mut Vec<PathBuf>,
+        failed_fragments: &HashSet<PathBuf>,
+    ) -> (String, ReferenceTracker, Vec<ItemError>) {
+        let mut references = ReferenceTracker::new();
+        let mut errors = Vec::new();
+        collect_fragments(
+            source,
+            settings,
+            chapter,
+            &mut BTreeMap::new(),
+            &mut references,
+            &mut errors,
+        );
+        let replaced = replace_blocks(
+            source,
+            settings,
+            chapter,
+            used_fragments,
+            &references,
+            failed_fragments,
+            &mut errors,
+        );
+        (replaced, references, errors)
+    }
+
+    /// The single fragment of `source`
+    fn fragment(chapter: &ChapterInfo, source: &str, settings: &Settings) -> Fragment {
+        let mut fragments = BTreeMap::new();
+        collect_fragments(
+            source,
+            settings,
+            chapter,
+            &mut fragments,
+            &mut ReferenceTracker::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(fragments.len(), 1);
+        fragments.into_values().next().unwrap()
+    }
+
+    #[test]
+    fn settings_follow_the_book_config() {
+        let template = FragmentTemplate::default();
+        let labels = Labels::default();
+        let cfg: Table = serde_json::from_value(serde_json::json!({
+            "svg_embedding": "inline",
+            "math": "mathml",
+            "epub_dpi": 0,
+        }))
+        .unwrap();
+        let path = Path::new("fragments");
+        let settings = Settings::from_cfg(
+            &cfg,
+            SupportedRenderer::Html,
+            Path::new(""),
+            path,
+            path,
+            &template,
+            &labels,
+        )
+        .unwrap();
+        assert_eq!(settings.svg_embedding, SvgEmbedding::Inline);
+        assert_eq!(settings.math, MathMode::MathMl);
+        assert_eq!(settings.raster_dpi, None);
+
+        let err = Settings::from_cfg(
+            &cfg,
+            SupportedRenderer::Epub,
+            Path::new(""),
+            path,
+            path,
+            &template,
+            &labels,
+        );
+        assert!(matches!(
+            err,
+            Err(ScientificError::InvalidConfigValue { ref key, .. }) if key == "epub_dpi"
+        ));
+    }
+
+    #[test]
+    fn all_failed_items_are_reported() {
+        let source = "See $ref:equ:missing$.\n\nAnd $ref:fig:absent$ with $x^2$.\n";
+        let (replaced, _, errors) = render(source, &settings());
+
+        let origins = Vec::from_iter(errors.iter().map(|err| err.origin.to_string()));
+        assert_eq!(origins, vec!["src/ch1.md:1:5", "src/ch1.md:3:5"]);
+        assert_eq!(replaced.matches("scientific_error").count(), 2);
+        assert!(replaced.contains(r"\\(x\^2\\)"));
+    }
+
+    #[test]
+    fn images_are_referenceable_figures() {
+        let source = "As $ref:fig:arch$ shows:\n\n![Overview](arch.png){#fig:arch}\n";
+        let (replaced, references, errors) = render(source, &settings());
+
+        assert!(errors.is_empty());
+        assert!(replaced.contains(r#"<a class="fig_ref" href='#arch'>Figure 1.1</a>"#));
+        assert!(replaced.contains(r#"<figure id="arch" class="figure">"#));
+        assert!(replaced.contains(r#"<img src="arch.png" alt="Overview" />"#));
+        assert!(replaced.contains("<figcaption>Figure 1.1 Overview</figcaption>"));
+
+        let listed = Vec::from_iter(references.listed(RefKind::Figure));
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].link, "ch1.md#arch");
+        assert_eq!(
+            format_list(&listed, "List of Figures", "Figure {}"),
+            "# List of Figures\n\n- [Figure 1.1](ch1.md#arch) Overview\n"
+        );
+    }
+
+    #[test]
+    fn references_preview_images_and_fragments() {
+        let settings = Settings {
+            math: MathMode::Svg,
+            reference_previews: true,
+            ..settings()
+        };
+        let chapter = ChapterInfo {
+            number: "2.",
+            path: Path::new("src/part/ch2.md"),
+            link: Path::new("part/ch2.md"),
+        };
+        let source = "See $ref:fig:arch$ and $ref:equ:euler$.\n\n![Overview](arch.png){#fig:arch}\n\n$$equation, euler\ne^{i\\pi} = -1\n$$\n";
+        let fragment = fragment(&chapter, source, &settings);
+
+        let mut used_fragments = Vec::new();
+        let (replaced, references, errors) = render_in(
+            &chapter,
+            source,
+            &settings,
+            &mut used_fragments,
+            &HashSet::new(),
+        );
+        assert_eq!(
+            references.preview("euler"),
+            Some(&Preview::Fragment {
+                fragment_file: fragment.fragment_file.clone(),
+                asset_file: fragment.asset_file.clone(),
+            })
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(replaced.contains(
+            r#"<a class="fig_ref" href='#arch'>Figure 2.1<span class="scientific_preview"><img src="../part/arch.png" alt="" loading="lazy"></span></a>"#
+        ));
+        assert!(replaced.contains(&format!(
+            r#"<span class="scientific_preview"><img src="{}" alt="" loading="lazy"></span>"#,
+            fragment.asset_file.display()
+        )));
+        assert!(used_fragments.contains(&fragment.fragment_file));
+        // styled without `scientific.css`
+        assert!(replaced.starts_with(PREVIEW_STYLE));
+        assert_eq!(replaced.matches("<style>").count(), 1);
+
+        // a failed fragment has nothing to show
+        let failed = HashSet::from([fragment.fragment_file.clone()]);
+        let (replaced, _, _) = render_in(&chapter, source, &settings, &mut Vec::new(), &failed);
+        assert_eq!(replaced.matches(r#"class="scientific_preview""#).count(), 1);
+    }
+
+    #[test]
+    fn fragments_differ_in_name_per_engine_and_embedding() {
+        let source = "$$equation, euler\ne^{i\\pi} = -1\n$$\n";
+        let name = |settings: Settings<'_>| {
+            let settings = Settings {
+                math: MathMode::Svg,
+                ..settings
+            };
+            let fragment = fragment(&chapter(), source, &settings);
+            (fragment.store_name(), fragment.input_hash())
+        };
+
+        let object = name(settings());
+        let inline = name(Settings {
+            svg_embedding: SvgEmbedding::Inline,
+            ..settings()
+        });
+        let xelatex = name(Settings {
+            engine: TexEngine::XeLatex,
+            ..settings()
+        });
+        assert_ne!(object.0, inline.0);
+        assert_ne!(object.0, xelatex.0);
+        assert_ne!(inline.0, xelatex.0);
+        assert_eq!(object, name(settings()));
+    }
+
+    #[test]
+    fn moving_the_book_keeps_plot_names() {
+        let dir = TempDir::new("moved");
+        let source = "$$gnuplot, runs, Runs\nplot 'run1.csv' using 1:2\n$$\n";
+        let names = Vec::from_iter(["a", "b"].map(|book| {
+            let root = dir.join(book);
+            fs::create_dir_all(root.join("src")).unwrap();
+            fs::write(root.join("src/run1.csv"), "1,2\n").unwrap();
+            let root = fs::canonicalize(root).unwrap();
+            let path = root.join("src/plots.md");
+            let chapter = ChapterInfo {
+                path: &path,
+                ..chapter()
+            };
+            let settings = Settings {
+                root: &root,
+                ..settings()
+            };
+            let fragment = fragment(&chapter, source, &settings);
+            assert_eq!(fragment.data.len(), 1);
+            fragment.store_name()
+        }));
+        assert_eq!(names[0], names[1]);
+    }
+
+    #[test]
+    fn python_plots_differ_in_stem_per_renderer() {
+        let source =
+            "$$python, wave, A sine\nimport numpy as np\nplt.plot(np.sin(np.arange(10)))\n$$\n";
+
+        let names = Vec::from_iter([SupportedRenderer::Html, SupportedRenderer::Latex].map(
+            |renderer| {
+                let settings = Settings {
+                    renderer,
+                    ..settings()
+                };
+                let fragment = fragment(&chapter(), source, &settings);
+                let name = fragment.fragment_file.file_name().unwrap().to_owned();
+                name.into_string().unwrap()
+            },
+        ));
+        assert!(names[0].ends_with(".svg"), "{}", names[0]);
+        assert_eq!(
+            names[1],
+            names[0].replace(".svg", "_pgf.pgf"),
+            "the svg and pgf of a plot must not share a stem"
+        );
+    }
+
+    #[test]
+    fn only_current_color_objects_are_inverted() {
+        let source = "Euler: $e^{i\\pi} = -1$\n";
+        let replaced = |svg_current_color| {
+            let settings = Settings {
+                math: MathMode::Svg,
+                svg_current_color,
+                ..settings()
+            };
+            render(source, &settings).0
+        };
+        assert!(replaced(true).contains(r#"<object class="equation_inline scientific_invert" "#));
+        assert!(replaced(false).contains(r#"<object class="equation_inline" "#));
+    }
+
+    #[test]
+    fn tables_with_math_stay_whole_figures() {
+        let source =
+            "| $x$ | 1 |\n|---|---|\n| $y$ | 2 |\n: Values of $x$ {#fig:t}\n\nSee $ref:fig:t$.\n";
+        let (replaced, _, errors) = render(source, &settings());
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(replaced.starts_with(
+            r#"<figure id="t" class="figure">
+
+| \\(x\\) | 1 |
+|---|---|
+| \\(y\\) | 2 |
+
+<figcaption>Figure 1.1 Values of \\(x\\)</figcaption>
+</figure>
+"#
+        ));
+        assert!(replaced.contains(r#"<a class="fig_ref" href='#t'>Figure 1.1</a>"#));
+    }
+
+    #[test]
+    fn latex_figures_are_labelled_environments() {
+        let settings = Settings {
+            renderer: SupportedRenderer::Latex,
+            ..settings()
+        };
+        let source =
+            "![Overview](arch.png){#fig:arch}\n\n| a | 1 |\n|---|---|\nTable: Values {#fig:t}\n";
+        let (replaced, _, errors) = render(source, &settings);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            replaced,
+            r"\begin{figure}[htbp]
+\centering
+\includegraphics[width=\textwidth]{arch.png}
+\caption{Overview}
+\label{arch}
+\end{figure}
+
+\begin{table}[htbp]
+\centering
+\caption{Values}
+\label{t}
+
+| a | 1 |
+|---|---|
+
+\end{table}
+"
+        );
+    }
+
+    #[test]
+    fn pandoc_flavour_uses_crossref_syntax() {
+        let settings = Settings {
+            renderer: SupportedRenderer::Markdown,
+            markdown_flavour: MarkdownFlavour::Pandoc,
+            ..settings()
+        };
+        let source = r"$$equation,pyth
+a^2 + b^2 = c^2
+$$
+
+See $ref:equ:pyth$ with $a$, $ref:bib:knuth$ and $ref:fig:runs$.
+
+| a |
+|---|
+: Runs {#fig:runs}
+";
+        let (replaced, _, errors) = render(source, &settings);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            replaced,
+            r"$$a^2 + b^2 = c^2$$ {#eq:pyth}
+
+See @eq:pyth with $a$, [@knuth] and @tbl:runs.
+
+| a |
+|---|
+: Runs {#tbl:runs}
+"
+        );
+    }
+
+    #[test]
+    fn chapter_key_follows_referenced_labels() {
+        let settings = settings();
+        let chapter = chapter();
+        let source = "As $ref:fig:arch$ shows, $x^2$.\n";
+
+        let mut references = ReferenceTracker::new();
+        let uses = collect_fragments(
+            source,
+            &settings,
+            &chapter,
+            &mut BTreeMap::new(),
+            &mut references,
+            &mut Vec::new(),
+        );
+        assert_eq!(uses.references, BTreeSet::from(["arch".to_owned()]));
+        let key = |references: &ReferenceTracker| {
+            uses.key(
+                source,
+                &settings,
+                &chapter,
+                references,
+                &HashMap::new(),
+                &HashSet::new(),
+            )
+        };
+
+        references.add("arch", "Figure 1.1");
+        let before = key(&references);
+        // labels the chapter does not reference do not matter
+        references.add("other", "Figure 1.2");
+        assert_eq!(key(&references), before);
+        // renumbering the referenced figure does
+        references.add("arch", "Figure 2.1");
+        assert_ne!(key(&references), before);
+    }
+
+    #[test]
+    fn duplicate_labels_are_reported_with_both_sites() {
+        let settings = settings();
+        let first = chapter();
+        let second = ChapterInfo {
+            number: "2.",
+            path: Path::new("src/ch2.md"),
+            link: Path::new("ch2.md"),
+        };
+        let energy = "$$equ,energy\nE = mc^2\n$$\n";
+        let source = "Intro\n\n$$equ,energy\nE = h \\nu\n$$\n\n![Plot](plot.png){#fig:plot}\n\nSee $ref:equ:enrgy$.\n";
+
+        let mut references = ReferenceTracker::new();
+        let mut errors = Vec::new();
+        for (chapter, source) in [(&first, energy), (&second, source)] {
+            collect_fragments(
+                source,
+                &settings,
+                chapter,
+                &mut BTreeMap::new(),
+                &mut references,
+                &mut errors,
+            );
+        }
+        let errors = Vec::from_iter(errors.iter().map(ToString::to_string));
+        assert_eq!(
+            errors,
+            vec!["src/ch2.md:3:1: Label `energy` is defined already at src/ch1.md:1:1"]
+        );
+        assert_eq!(references.get("energy").as_deref(), Some("1.1"));
+
+        let unused = Vec::from_iter(
+            references
+                .unused(&HashSet::from(["energy"]))
+                .into_iter()
+                .map(|(label, origin)| format!("{} {}", label, origin)),
+        );
+        assert_eq!(unused, vec!["plot src/ch2.md:7:1"]);
+
+        let mut errors = Vec::new();
+        replace_blocks(
+            source,
+            &settings,
+            &second,
+            &mut Vec::new(),
+            &references,
+            &HashSet::new(),
+            &mut errors,
+        );
+        let err = errors[0].source.to_string();
+        assert!(err.ends_with(", did you mean `energy`?"), "{}", err);
+    }
 }
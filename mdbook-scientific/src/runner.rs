@@ -0,0 +1,202 @@
+//! Execution of the external tools, i.e. `latex`, `dvisvgm` and `gnuplot`.
+
+use std::io::{Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mdbook_boilerplate::Table;
+
+use crate::config;
+use crate::errors::{Result, ScientificError};
+
+/// Runs tools to completion, capturing their output and killing them after a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Runner {
+    pub timeout: Duration,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Runner {
+    /// Read `tool_timeout` in seconds, which must not be zero.
+    pub fn from_cfg(cfg: &Table) -> Result<Self> {
+        let mut runner = Self::default();
+        if let Some(secs) = config::get_integer_in(cfg, "tool_timeout", 1..=usize::MAX)? {
+            runner.timeout = Duration::from_secs(secs as u64);
+        }
+        Ok(runner)
+    }
+
+    /// Run `command` feeding it `stdin`, regardless of its exit status.
+    ///
+    /// Only fails if the tool could not be started or did not finish in time. After a timeout the
+    /// pipes are left to their reader threads, processes spawned by the tool may keep them open.
+    pub fn run(&self, command: &mut Command, stdin: Option<&[u8]>) -> Result<Output> {
+        let cmd = command_line(command);
+        log::debug!("Running `{}`", cmd);
+
+        let mut child = command
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| ScientificError::ToolSpawn {
+                cmd: cmd.clone(),
+                source,
+            })?;
+
+        // feed stdin and drain the pipes concurrently, a tool blocks once a pipe is full
+        if let (Some(mut pipe), Some(input)) = (child.stdin.take(), stdin) {
+            let input = input.to_vec();
+            // closing the pipe signals the end of input
+            thread::spawn(move || pipe.write_all(&input));
+        }
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                log::warn!("Killing `{}` after {:?}", cmd, self.timeout);
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ScientificError::ToolTimeout {
+                    cmd,
+                    timeout: self.timeout,
+                });
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().expect("Reading a pipe does not panic. qed"),
+            stderr: stderr.join().expect("Reading a pipe does not panic. qed"),
+        })
+    }
+
+    /// Run `command` like [`Runner::run`], but also fail if it exits unsuccessfully.
+    pub fn run_checked(&self, command: &mut Command, stdin: Option<&[u8]>) -> Result<Output> {
+        let output = self.run(command, stdin)?;
+        if output.status.success() {
+            Ok(output)
+        } else {
+            Err(failed(command, &output))
+        }
+    }
+}
+
+/// The error for a tool that exited unsuccessfully, with whatever it printed.
+pub fn failed(command: &Command, output: &Output) -> ScientificError {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    ScientificError::ToolFailed {
+        cmd: command_line(command),
+        status: output.status,
+        output: if stderr.trim().is_empty() {
+            stdout.trim().to_owned()
+        } else {
+            stderr.trim().to_owned()
+        },
+    }
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// The program with its arguments, for error messages
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_output_and_stdin() {
+        let output = Runner::default()
+            .run_checked(&mut Command::new("cat"), Some(b"set terminal svg\n"))
+            .unwrap();
+        assert_eq!(output.stdout, b"set terminal svg\n");
+    }
+
+    #[test]
+    fn hung_tools_are_killed() {
+        let runner = Runner {
+            timeout: Duration::from_millis(100),
+        };
+        let err = runner
+            .run(Command::new("sleep").arg("10"), None)
+            .unwrap_err();
+        assert!(matches!(err, ScientificError::ToolTimeout { ref cmd, .. } if cmd == "sleep 10"));
+    }
+
+    #[test]
+    fn timeout_does_not_wait_for_grandchildren() {
+        let runner = Runner {
+            timeout: Duration::from_millis(100),
+        };
+        let started = Instant::now();
+        // the background `sleep` inherits stdout and keeps it open after `sh` is killed
+        let err = runner
+            .run(Command::new("sh").args(["-c", "sleep 10 & sleep 10"]), None)
+            .unwrap_err();
+        assert!(matches!(err, ScientificError::ToolTimeout { .. }));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn zero_timeout_is_rejected() {
+        let mut cfg = Table::new();
+        cfg.insert("tool_timeout".to_owned(), 0.into());
+        assert!(matches!(
+            Runner::from_cfg(&cfg),
+            Err(ScientificError::InvalidConfigValue { ref key, .. }) if key == "tool_timeout"
+        ));
+        cfg.insert("tool_timeout".to_owned(), 5.into());
+        assert_eq!(
+            Runner::from_cfg(&cfg).unwrap().timeout,
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn failures_carry_the_command_line() {
+        let err = Runner::default()
+            .run_checked(
+                Command::new("sh").args(["-c", "echo broken >&2; exit 3"]),
+                None,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`sh -c echo broken >&2; exit 3` failed (exit status: 3):\nbroken"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Preparation of rendered svg files for embedding their markup into html.
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+/// Font size of the fragment template, TeX sizes are given relative to it as `em`.
+const TEX_FONT_SIZE_PT: f32 = 10.0;
+
+/// Options for the conversion to svg by `dvisvgm`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SvgOptions {
+    pub zoom: f32,
+    /// Embed fonts in the woff format, otherwise glyphs are converted to paths
+    pub fonts: bool,
+}
+
+/// Extents of the typeset equation, as computed by the preview package.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SvgMetrics {
+    /// In TeX points, excluding zoom and border
+    pub width: f32,
+    pub height: f32,
+    pub depth: f32,
+}
+
+impl SvgMetrics {
+    /// Parse the `width=..pt, height=..pt, depth=..pt` line `dvisvgm` prints.
+    pub fn from_dvisvgm(output: &str) -> Option<Self> {
+        let re = Regex::new(r"width=([\d.]+)pt, height=([\d.]+)pt, depth=([\d.]+)pt")
+            .expect("Regex is valid. qed");
+        let captures = re.captures(output)?;
+        let pt = |idx: usize| captures[idx].parse::<f32>().ok();
+        Some(Self {
+            width: pt(1)?,
+            height: pt(2)?,
+            depth: pt(3)?,
+        })
+    }
+}
+
+/// Svg markup ready to be placed into html.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineSvg {
+    pub markup: String,
+    /// Distance of the baseline from the bottom edge in `em`, if known
+    pub depth: Option<f32>,
+}
+
+/// Size of a rendered svg in `em`, to show a raster image of it at text size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Extents {
+    pub width: f32,
+    pub height: f32,
+    /// Distance of the baseline from the bottom edge, if known
+    pub depth: Option<f32>,
+}
+
+/// The extents of the svg `dvisvgm` scaled by `zoom`, from its root element.
+pub fn extents(svg: &str, zoom: f32, metrics: Option<&SvgMetrics>) -> Option<Extents> {
+    let svg = &svg[svg.find("<svg")?..];
+    let root = &svg[..svg.find('>').unwrap_or(svg.len())];
+    let pt = |attr: &str| {
+        Regex::new(&format!(r#"\b{}=['"]([\d.]+)pt['"]"#, attr))
+            .expect("Regex is valid. qed")
+            .captures(root)
+            .and_then(|captures| captures[1].parse::<f32>().ok())
+            .map(|pt| pt / zoom)
+    };
+    let (width, height) = (pt("width")?, pt("height")?);
+    Some(Extents {
+        width: width / TEX_FONT_SIZE_PT,
+        height: height / TEX_FONT_SIZE_PT,
+        depth: metrics.map(|metrics| baseline_depth(metrics, height)),
+    })
+}
+
+/// Depth in `em`, the border dvisvgm adds around the equation extends below the baseline as well.
+fn baseline_depth(metrics: &SvgMetrics, svg_height: f32) -> f32 {
+    let border = ((svg_height - metrics.height - metrics.depth) / 2.0).max(0.0);
+    (metrics.depth + border) / TEX_FONT_SIZE_PT
+}
+
+/// Replace black, explicit or implied, by `currentColor` so the svg follows the text color.
+pub fn current_color(svg: &str) -> String {
+    let black = Regex::new(
+        r#"\b(fill|stroke|color)(=['"]|\s*:\s*)(#000000|#000|black|rgb\(\s*0\s*,\s*0\s*,\s*0\s*\))"#,
+    )
+    .expect("Regex is valid. qed");
+    let svg = black.replace_all(svg, "${1}${2}currentColor");
+
+    // glyphs and rules without any fill default to black
+    match svg.find("<svg") {
+        Some(idx) => {
+            let (head, root) = svg.split_at(idx + "<svg".len());
+            let root_end = root.find('>').unwrap_or(root.len());
+            if Regex::new(r"\sfill=")
+                .expect("Regex is valid. qed")
+                .is_match(&root[..root_end])
+            {
+                svg.into_owned()
+            } else {
+                format!("{} fill='currentColor'{}", head, root)
+            }
+        }
+        None => svg.into_owned(),
+    }
+}
+
+/// Strip the xml prolog, make all ids unique by `id_prefix` and size the svg in `em`.
+///
+/// `zoom` is the factor `dvisvgm` scaled the svg by, undone for the `em` sizes.
+pub fn inline(
+    svg: &str,
+    id_prefix: &str,
+    zoom: f32,
+    metrics: Option<&SvgMetrics>,
+    follow_text_color: bool,
+) -> InlineSvg {
+    let svg = &svg[svg.find("<svg").unwrap_or_default()..];
+    let recolored;
+    let svg = if follow_text_color {
+        recolored = current_color(svg);
+        recolored.as_str()
+    } else {
+        svg
+    };
+
+    // several svgs share one html document, so their glyph and clip path ids must not collide
+    let ids = Regex::new(r#"\b(id=|href=)(['"])(#?)([^'"]+)(['"])"#).expect("Regex is valid. qed");
+    let svg = ids.replace_all(svg, |captures: &Captures| {
+        let is_reference = captures[1].ends_with("href=") && captures[3].is_empty();
+        if is_reference {
+            // an external link, not an id
+            captures[0].to_owned()
+        } else {
+            format!(
+                "{}{}{}{}-{}{}",
+                &captures[1], &captures[2], &captures[3], id_prefix, &captures[4], &captures[5]
+            )
+        }
+    });
+    let urls = Regex::new(r"url\(#([^)]+)\)").expect("Regex is valid. qed");
+    let svg = urls.replace_all(&svg, |captures: &Captures| {
+        format!("url(#{}-{})", id_prefix, &captures[1])
+    });
+
+    // the root element sizes, in points for dvisvgm
+    let root_end = svg.find('>').unwrap_or(svg.len());
+    let (root, body) = svg.split_at(root_end);
+    let size = Regex::new(r#"\b(width|height)=['"]([\d.]+)pt['"]"#).expect("Regex is valid. qed");
+    let mut svg_height = None;
+    let root = size.replace_all(root, |captures: &Captures| {
+        let pt = captures[2].parse::<f32>().unwrap_or_default() / zoom;
+        if &captures[1] == "height" {
+            svg_height = Some(pt);
+        }
+        format!("{}='{:.4}em'", &captures[1], pt / TEX_FONT_SIZE_PT)
+    });
+
+    let depth = metrics
+        .zip(svg_height)
+        .map(|(metrics, svg_height)| baseline_depth(metrics, svg_height));
+
+    // no line breaks, so markdown keeps the markup within a paragraph
+    let markup = format!("{}{}", root, body)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    InlineSvg { markup, depth }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg_is_sized_in_em_with_unique_ids() {
+        let svg = r#"<?xml version='1.0' encoding='UTF-8'?>
+<svg version='1.1' xmlns='http://www.w3.org/2000/svg' xmlns:xlink='http://www.w3.org/1999/xlink' width='26pt' height='10.4pt' viewBox='0 0 20 12'>
+<defs><path id='g0-120' d='M1 1'/><clipPath id='c0'><path d='M0 0'/></clipPath></defs>
+<g clip-path='url(#c0)'><use x='1' y='9' xlink:href='#g0-120'/></g>
+<a href='https://example.com'/>
+</svg>"#;
+        let metrics = SvgMetrics::from_dvisvgm(
+            "processing page 1\n  width=5.69pt, height=4.31pt, depth=1.69pt\n",
+        )
+        .unwrap();
+        let inlined = inline(svg, "scientific_1__abc", 1.3, Some(&metrics), false);
+
+        assert!(inlined.markup.starts_with("<svg"));
+        assert!(inlined
+            .markup
+            .contains("width='2.0000em' height='0.8000em'"));
+        assert!(inlined.markup.contains("id='scientific_1__abc-g0-120'"));
+        assert!(inlined
+            .markup
+            .contains("xlink:href='#scientific_1__abc-g0-120'"));
+        assert!(inlined.markup.contains("url(#scientific_1__abc-c0)"));
+        assert!(inlined.markup.contains("href='https://example.com'"));
+        // 8pt high, 6pt of it typeset, the remaining 1pt border on each side
+        let depth = inlined.depth.unwrap();
+        assert!((depth - 0.269).abs() < 1e-3, "{}", depth);
+
+        let extents = extents(svg, 1.3, Some(&metrics)).unwrap();
+        assert!((extents.width - 2.0).abs() < 1e-3);
+        assert!((extents.height - 0.8).abs() < 1e-3);
+        assert_eq!(extents.depth, inlined.depth);
+    }
+
+    #[test]
+    fn black_becomes_current_color() {
+        let svg = r#"<svg version='1.1' width='26pt'><rect fill='#000' height='1'/><path stroke="black" style="fill:rgb(0, 0, 0)"/><path fill='#ff0000'/></svg>"#;
+        assert_eq!(
+            current_color(svg),
+            r#"<svg fill='currentColor' version='1.1' width='26pt'><rect fill='currentColor' height='1'/><path stroke="currentColor" style="fill:currentColor"/><path fill='#ff0000'/></svg>"#
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! The latex document equations are spliced into before rendering.
+//!
+//! Either the built-in `fragment.tex` or a user provided template, amended with the configured
+//! preamble and fonts.
+
+use fs_err as fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use mdbook_boilerplate::Table;
+
+use crate::config;
+use crate::engine::TexEngine;
+use crate::errors::{Result, ScientificError};
+
+const BUILTIN: &str = include_str!("fragment.tex");
+const BEGIN_DOCUMENT: &str = "\\begin{document}";
+
+/// A complete latex document with exactly two `$$`, the equation goes in between.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentTemplate(Arc<str>);
+
+impl Default for FragmentTemplate {
+    fn default() -> Self {
+        Self(Arc::from(BUILTIN))
+    }
+}
+
+impl FragmentTemplate {
+    /// Read `fragment_template`, relative to the book `root`, and add `preamble`,
+    /// `main_font` and `math_font`.
+    pub fn from_cfg(cfg: &Table, root: &Path, engine: TexEngine) -> Result<Self> {
+        let (name, template) = match config::get_str(cfg, "fragment_template")? {
+            Some(path) => (path, fs::read_to_string(root.join(path))?),
+            None => ("fragment.tex", BUILTIN.to_owned()),
+        };
+        let invalid = |msg: &str| ScientificError::InvalidTemplate {
+            path: name.to_owned(),
+            msg: msg.to_owned(),
+        };
+        if template.matches("$$").count() != 2 {
+            return Err(invalid("must contain exactly two `$$`"));
+        }
+
+        let mut preamble = Vec::from_iter(
+            config::get_str_list(cfg, "preamble")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(str::to_owned),
+        );
+        let fonts = [
+            ("main_font", "fontspec", "setmainfont"),
+            ("math_font", "unicode-math", "setmathfont"),
+        ];
+        for (key, package, command) in fonts {
+            if let Some(font) = config::get_str(cfg, key)? {
+                if !engine.is_unicode() {
+                    return Err(ScientificError::FontsRequireUnicodeEngine(
+                        engine.as_str().to_owned(),
+                    ));
+                }
+                preamble.push(format!("\\usepackage{{{}}}", package));
+                preamble.push(format!("\\{}{{{}}}", command, font));
+            }
+        }
+        if preamble.is_empty() {
+            return Ok(Self(Arc::from(template)));
+        }
+
+        let begin = template
+            .find(BEGIN_DOCUMENT)
+            .ok_or_else(|| invalid("must contain `\\begin{document}`"))?;
+        let (head, body) = template.split_at(begin);
+        Ok(Self(Arc::from(format!(
+            "{}{}\n{}",
+            head,
+            preamble.join("\n"),
+            body
+        ))))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Line of the opening `$$`, base 1
+    pub fn equation_line(&self) -> usize {
+        self.0
+            .split("$$")
+            .next()
+            .unwrap_or_default()
+            .matches('\n')
+            .count()
+            + 1
+    }
+
+    /// The document with `tex` as equation
+    pub fn splice(&self, tex: &str) -> String {
+        let mut parts = self.0.splitn(3, "$$");
+        let head = parts.next().unwrap_or_default();
+        let _placeholder = parts.next();
+        let tail = parts.next().unwrap_or_default();
+        format!("{}$${}$${}", head, tex, tail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preamble_and_fonts_precede_the_document() {
+        let cfg: Table = serde_json::from_value(serde_json::json!({
+            "preamble": [r"\usepackage{physics}", r"\usepackage{siunitx}"],
+            "math_font": "Fira Math",
+        }))
+        .unwrap();
+        let template =
+            FragmentTemplate::from_cfg(&cfg, Path::new("."), TexEngine::XeLatex).unwrap();
+        let document = template.splice(r"\qty{3}{\m}");
+        assert!(document.contains(
+            "\\usepackage{physics}\n\\usepackage{siunitx}\n\\usepackage{unicode-math}\n\\setmathfont{Fira Math}\n\\begin{document}"
+        ));
+        assert!(document.contains(r"$$\qty{3}{\m}$$"));
+        assert_eq!(
+            template.equation_line(),
+            FragmentTemplate::default().equation_line() + 4
+        );
+
+        let err = FragmentTemplate::from_cfg(&cfg, Path::new("."), TexEngine::PdfLatex);
+        assert!(matches!(
+            err,
+            Err(ScientificError::FontsRequireUnicodeEngine(_))
+        ));
+    }
+}
@@ -55,3 +55,143 @@
 // }
 
 // test_end2end!(basic, "a $b$ c" => "a ", "b", "c");
+
+use super::*;
+use std::ops::Deref;
+
+/// A fresh directory below the system temp dir, removed again on drop, even if a test fails
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` keeps the directories of concurrent tests apart
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("scientific-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("Temp dir is writable. qed");
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn chapter(name: &str, path: &str, sub_items: Vec<BookItem>) -> BookItem {
+    let mut chapter = Chapter::new(name, format!("# {}\n", name), path, Vec::new());
+    chapter.sub_items = sub_items;
+    BookItem::Chapter(chapter)
+}
+
+fn book() -> Book {
+    let nested = Chapter::new(
+        "Nested",
+        "# Nested\n".to_owned(),
+        "part/nested.md",
+        vec!["Part".to_owned()],
+    );
+    let mut book = Book::new();
+    book.push_item(chapter("Intro", "intro.md", Vec::new()));
+    book.push_item(chapter("Part", "part.md", vec![BookItem::Chapter(nested)]));
+    book.push_item(chapter("Appendix", "appendix.md", Vec::new()));
+    book
+}
+
+fn names(items: &[BookItem]) -> Vec<&str> {
+    Vec::from_iter(items.iter().filter_map(|item| match item {
+        BookItem::Chapter(ch) => Some(ch.name.as_str()),
+        _ => None,
+    }))
+}
+
+#[test]
+fn lists_are_inserted_before_a_nested_chapter() {
+    let cfg: Table = serde_json::from_value(serde_json::json!({
+        "list_of_figures": true,
+        "list_of_equations": true,
+        "lists_before": "part/nested.md",
+    }))
+    .unwrap();
+    let mut references = ReferenceTracker::new();
+    references.list(Listed {
+        kind: RefKind::Figure,
+        number: "2.1".to_owned(),
+        caption: "Overview".to_owned(),
+        link: "part/nested.md#arch".to_owned(),
+    });
+    let mut book = book();
+    add_lists(&cfg, &mut book, &references, &Labels::default()).unwrap();
+
+    assert_eq!(names(&book.sections), ["Intro", "Part", "Appendix"]);
+    let BookItem::Chapter(ref part) = book.sections[1] else {
+        panic!("the part is a chapter");
+    };
+    assert_eq!(
+        names(&part.sub_items),
+        ["List of Figures", "List of Equations", "Nested"]
+    );
+    let BookItem::Chapter(ref figures) = part.sub_items[0] else {
+        panic!("the list is a chapter");
+    };
+    assert_eq!(figures.parent_names, ["Part"]);
+    assert_eq!(
+        figures.content,
+        "# List of Figures\n\n- [Figure 2.1](part/nested.md#arch) Overview\n"
+    );
+}
+
+#[test]
+fn lists_are_appended_without_lists_before() {
+    let cfg: Table = serde_json::from_value(serde_json::json!({
+        "list_of_equations": true,
+    }))
+    .unwrap();
+    let mut book = book();
+    add_lists(
+        &cfg,
+        &mut book,
+        &ReferenceTracker::new(),
+        &Labels::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        names(&book.sections),
+        ["Intro", "Part", "Appendix", "List of Equations"]
+    );
+}
+
+#[test]
+fn unknown_lists_before_is_rejected() {
+    let cfg: Table = serde_json::from_value(serde_json::json!({
+        "list_of_figures": true,
+        "lists_before": "missing.md",
+    }))
+    .unwrap();
+    let mut book = book();
+    let err = add_lists(
+        &cfg,
+        &mut book,
+        &ReferenceTracker::new(),
+        &Labels::default(),
+    );
+    assert!(matches!(
+        err,
+        Err(ScientificError::InvalidConfigValue { ref key, .. }) if key == "lists_before"
+    ));
+    assert_eq!(names(&book.sections), ["Intro", "Part", "Appendix"]);
+}
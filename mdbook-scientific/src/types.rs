@@ -1,12 +1,61 @@
 use crate::errors;
+use crate::svg::{Extents, InlineSvg};
 pub(crate) use mathyank::types::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// A numbered figure or equation, as shown in the generated lists
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listed {
+    pub kind: RefKind,
+    /// Chapter number and counter, i.e. `1.2`
+    pub number: String,
+    pub caption: String,
+    /// Chapter file relative to the source directory, with the anchor of the item
+    pub link: String,
+}
+
+/// The relative path from the chapter `link` to the source directory, i.e. `../` for `part/ch.md`
+pub fn path_to_root(link: &Path) -> String {
+    "../".repeat(link.components().count().saturating_sub(1))
+}
+
+/// What a reference shows when hovered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Preview {
+    /// A rendered equation or figure
+    Fragment {
+        fragment_file: PathBuf,
+        asset_file: PathBuf,
+    },
+    /// An image, relative to the source directory unless it is a url
+    Image(String),
+}
+
+impl Preview {
+    /// The `src` to use within the chapter `link`, relative to the source directory.
+    pub fn src(&self, link: &Path) -> String {
+        match self {
+            Self::Fragment { asset_file, .. } => asset_file.display().to_string(),
+            Self::Image(url) if url.contains("://") => url.clone(),
+            Self::Image(path) => format!("{}{}", path_to_root(link), path),
+        }
+    }
+}
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
-pub struct ReferenceTracker(HashMap<String, String>);
+pub struct ReferenceTracker {
+    titles: HashMap<String, String>,
+    /// Where the labels of equations and figures are defined
+    definitions: HashMap<String, Origin>,
+    previews: HashMap<String, Preview>,
+    /// Figures that are tables, which pandoc-crossref labels differently
+    tables: HashSet<String>,
+    /// In book order
+    listed: Vec<Listed>,
+}
 
 impl ReferenceTracker {
     pub fn new() -> Self {
@@ -15,16 +64,113 @@ impl ReferenceTracker {
 
     pub fn add(&mut self, key: impl AsRef<str>, title: impl AsRef<str>) {
         let key = key.as_ref();
-        log::warn!("Addind reference `{}`", key);
-        self.0.insert(key.to_string(), title.as_ref().to_string());
+        log::debug!("Adding reference `{}`", key);
+        self.titles
+            .insert(key.to_string(), title.as_ref().to_string());
+    }
+
+    /// Add the label of an equation or figure, unless it is defined already.
+    pub fn define(
+        &mut self,
+        key: impl AsRef<str>,
+        title: impl AsRef<str>,
+        origin: Origin,
+    ) -> Result<(), errors::ScientificError> {
+        let key = key.as_ref();
+        if let Some(first) = self.definitions.get(key) {
+            return Err(errors::ScientificError::DuplicateLabel {
+                label: key.to_owned(),
+                first: first.to_string(),
+            });
+        }
+        self.add(key, title);
+        self.definitions.insert(key.to_owned(), origin);
+        Ok(())
+    }
+
+    /// The defined labels not in `referenced`, in book order.
+    pub fn unused(&self, referenced: &HashSet<&str>) -> Vec<(&str, &Origin)> {
+        let mut unused = Vec::from_iter(
+            self.definitions
+                .iter()
+                .filter(|(key, _)| !referenced.contains(key.as_str()))
+                .map(|(key, origin)| (key.as_str(), origin)),
+        );
+        unused.sort_by(|a, b| a.1.cmp(b.1));
+        unused
+    }
+
+    /// The known label closest to `key`, if it is a likely misspelling.
+    pub fn suggest(&self, key: impl AsRef<str>) -> Option<&str> {
+        let key = key.as_ref();
+        // allow one typo per three characters
+        let max = (key.chars().count() / 3).max(1);
+        self.titles
+            .keys()
+            .map(|known| (edit_distance(key, known), known))
+            .filter(|(distance, _)| *distance <= max)
+            .min()
+            .map(|(_, known)| known.as_str())
     }
 
     pub fn get(&self, key: impl AsRef<str>) -> Option<String> {
         let key = key.as_ref();
-        let maybe_value = self.0.get(key);
+        let maybe_value = self.titles.get(key);
         log::warn!("Lookup of reference `{}` yieled `{:?}`", key, &maybe_value);
         maybe_value.cloned()
     }
+
+    pub fn add_table(&mut self, key: impl AsRef<str>) {
+        self.tables.insert(key.as_ref().to_string());
+    }
+
+    pub fn is_table(&self, key: impl AsRef<str>) -> bool {
+        self.tables.contains(key.as_ref())
+    }
+
+    pub fn add_preview(&mut self, key: impl AsRef<str>, preview: Preview) {
+        self.previews.insert(key.as_ref().to_string(), preview);
+    }
+
+    pub fn preview(&self, key: impl AsRef<str>) -> Option<&Preview> {
+        self.previews.get(key.as_ref())
+    }
+
+    /// Everything known about `key`, to tell whether a reference to it renders differently.
+    pub fn resolution(&self, key: impl AsRef<str>) -> String {
+        let key = key.as_ref();
+        format!(
+            "{:?}\0{:?}\0{}",
+            self.titles.get(key),
+            self.previews.get(key),
+            self.tables.contains(key)
+        )
+    }
+
+    /// Record a numbered item for the list of figures or equations.
+    pub fn list(&mut self, listed: Listed) {
+        self.listed.push(listed);
+    }
+
+    pub fn listed(&self, kind: RefKind) -> impl Iterator<Item = &Listed> {
+        self.listed.iter().filter(move |listed| listed.kind == kind)
+    }
+}
+
+/// Levenshtein distance of two strings, in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = Vec::from_iter(b.chars());
+    let mut row = Vec::from_iter(0..=b.len());
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
 }
 
 /// Enum covering all supported renderers
@@ -36,6 +182,8 @@ pub enum SupportedRenderer {
     Latex,
     Markdown,
     Html,
+    /// Html for `mdbook-epub`, without svg objects
+    Epub,
 }
 
 impl FromStr for SupportedRenderer {
@@ -47,22 +195,265 @@ impl FromStr for SupportedRenderer {
             "latex" => Self::Latex,
             "markdown" => Self::Markdown,
             "html" => Self::Html,
+            "epub" => Self::Epub,
             s => return Err(errors::ScientificError::RendererNotSupported(s.to_owned())),
         })
     }
 }
 
-/// Parsed content reference with a path to the replacement svg
+/// How equations are represented in the html and markdown output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MathMode {
+    /// Render with `latex` and `dvisvgm` to svg files
+    #[default]
+    Svg,
+    /// Convert to MathML in-process, no TeX distribution required
+    MathMl,
+    /// Emit the TeX source for client-side rendering with MathJax or KaTeX
+    Passthrough,
+}
+
+impl MathMode {
+    /// Equations are handled without any external tools
+    pub fn is_in_process(&self) -> bool {
+        !matches!(self, Self::Svg)
+    }
+}
+
+impl FromStr for MathMode {
+    type Err = errors::ScientificError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "svg" => Self::Svg,
+            "mathml" => Self::MathMl,
+            "passthrough" => Self::Passthrough,
+            s => {
+                return Err(errors::ScientificError::InvalidConfigValue {
+                    key: "math".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// How equations are represented in epub output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EpubMath {
+    /// Rendered to svg and rasterized, readable by every e-reader
+    #[default]
+    Png,
+    /// Converted to MathML in-process
+    MathMl,
+}
+
+impl FromStr for EpubMath {
+    type Err = errors::ScientificError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "png" => Self::Png,
+            "mathml" => Self::MathMl,
+            s => {
+                return Err(errors::ScientificError::InvalidConfigValue {
+                    key: "epub_math".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// The dialect written by the markdown renderer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MarkdownFlavour {
+    /// Html snippets, as for the html renderer
+    #[default]
+    Html,
+    /// Markdown with pandoc-crossref labels and references, math as TeX
+    Pandoc,
+}
+
+impl FromStr for MarkdownFlavour {
+    type Err = errors::ScientificError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "html" => Self::Html,
+            "pandoc" => Self::Pandoc,
+            s => {
+                return Err(errors::ScientificError::InvalidConfigValue {
+                    key: "markdown_flavour".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// How rendered svg files are embedded into html
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SvgEmbedding {
+    /// Reference the file with an `<object>` tag
+    #[default]
+    Object,
+    /// Insert the svg markup, sized in `em` and aligned to the baseline
+    Inline,
+}
+
+impl SvgEmbedding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Object => "object",
+            Self::Inline => "inline",
+        }
+    }
+}
+
+impl FromStr for SvgEmbedding {
+    type Err = errors::ScientificError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "object" => Self::Object,
+            "inline" => Self::Inline,
+            s => {
+                return Err(errors::ScientificError::InvalidConfigValue {
+                    key: "svg_embedding".to_owned(),
+                    value: s.to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// The rendered form of a replacement
+#[derive(Debug, Clone)]
+pub enum Rendered {
+    /// A file that is referenced from the output
+    File {
+        /// The rendered svg within the fragment path
+        fragment_file: PathBuf,
+        /// Where the file is copied to, as referenced from the output
+        asset_file: PathBuf,
+    },
+    /// A png rendered from the svg, shown at the size of the svg
+    Raster {
+        fragment_file: PathBuf,
+        asset_file: PathBuf,
+        extents: Option<Extents>,
+    },
+    /// Svg markup that is embedded into the output as is
+    InlineSvg(InlineSvg),
+    /// MathML markup that is embedded into the output as is
+    MathMl(String),
+    /// The TeX source, left for rendering in the browser
+    Passthrough,
+}
+
+impl Rendered {
+    pub fn svg(fragment_file: PathBuf, asset_file: PathBuf) -> Self {
+        Self::File {
+            fragment_file,
+            asset_file,
+        }
+    }
+
+    /// The file that must be copied to the assets, if any
+    pub fn fragment_file(&self) -> Option<&Path> {
+        match self {
+            Self::File { fragment_file, .. } | Self::Raster { fragment_file, .. } => {
+                Some(fragment_file.as_path())
+            }
+            Self::InlineSvg(_) | Self::MathMl(_) | Self::Passthrough => None,
+        }
+    }
+}
+
+impl SupportedRenderer {
+    /// Renderers that consume html snippets rather than latex
+    pub fn is_html_like(&self) -> bool {
+        matches!(self, Self::Html | Self::Markdown | Self::Epub)
+    }
+}
+
+/// Where an item was found in the book source, ordered by position
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Origin {
+    /// Chapter source file, as found in the book directory i.e. `src/ch2.md`
+    pub chapter_path: PathBuf,
+    /// Base 1 line of the opening delimiter
+    pub lineno: usize,
+    /// Base 1 column of the opening delimiter
+    pub column: usize,
+    /// The complete source lines spanned by the item
+    pub lines: Vec<String>,
+}
+
+impl Origin {
+    /// Determine the origin of `content` within the chapter `source`
+    pub fn new(chapter_path: &Path, source: &str, content: &Content<'_>) -> Self {
+        Self::at(chapter_path, source, content.byte_range.clone())
+    }
+
+    /// Determine the origin of the bytes `range` of the chapter `source`
+    pub fn at(chapter_path: &Path, source: &str, range: std::ops::Range<usize>) -> Self {
+        let start = range.start.min(source.len());
+        let end = range.end.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = source[end..]
+            .find('\n')
+            .map_or(source.len(), |idx| end + idx);
+        Self {
+            chapter_path: chapter_path.to_owned(),
+            lineno: source[..start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+            lines: Vec::from_iter(source[line_start..line_end].lines().map(str::to_owned)),
+        }
+    }
+
+    /// Render the source line `lineno` with a marker below `column`, both base 1.
+    pub fn snippet(&self, lineno: usize, column: usize) -> String {
+        let line = lineno
+            .checked_sub(self.lineno)
+            .and_then(|idx| self.lines.get(idx))
+            .map(String::as_str)
+            .unwrap_or_default();
+        let width = lineno.to_string().len();
+        format!(
+            "{empty:width$} |\n{lineno} | {line}\n{empty:width$} | {empty:offset$}^",
+            empty = "",
+            width = width,
+            lineno = lineno,
+            line = line,
+            offset = column.saturating_sub(1),
+        )
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.chapter_path.display(),
+            self.lineno,
+            self.column
+        )
+    }
+}
+
+/// Parsed content reference with its rendered form
 #[derive(Debug)]
 pub struct Replacement<'a> {
     pub content: Content<'a>,
 
     /// Intermediate representation if there is any, directly usable with latex/tectonic backends;.
     pub(crate) intermediate: Option<String>,
-    /// Path to the rendered context, possibly an svg or pdf or png file. Double check.
-    pub svg_fragment_file: PathBuf,
-    /// Path where the file will ulitmately reside in, must be used for referencing in output
-    pub svg_asset_file: PathBuf,
+    pub rendered: Rendered,
+    /// MathML shown by browsers that fail to load a referenced svg
+    pub(crate) fallback: Option<String>,
 }
 
 impl<'a> Replacement<'a> {
@@ -74,3 +465,18 @@ impl<'a> Replacement<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_previews_are_relative_to_the_chapter() {
+        let preview = Preview::Image("part/img/arch.png".to_owned());
+        assert_eq!(preview.src(Path::new("intro.md")), "part/img/arch.png");
+        assert_eq!(
+            preview.src(Path::new("other/deep/ch.md")),
+            "../../part/img/arch.png"
+        );
+    }
+}